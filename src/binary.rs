@@ -0,0 +1,254 @@
+// binary.rs - A compact binary encoding of values for the Clarice programming language
+
+use std::rc::Rc;
+
+use crate::interpreter::{force_list, Key, Map, Value};
+
+/// The first byte of every encoding, so that a later change to the format
+/// can tell old bytes apart instead of misreading them.
+const VERSION: u8 = 1;
+
+// What kind of value comes next. Integers, doubles and lengths are written
+// as 8 bytes, least significant first.
+const VOID: u8 = 0;
+const INTEGER: u8 = 1;
+const DOUBLE: u8 = 2;
+const STRING: u8 = 3;
+const BOOLEAN: u8 = 4;
+const LIST: u8 = 5;
+const MAP: u8 = 6;
+
+/// Encodes a value as bytes, which `read` turns back into exactly the same
+/// value, down to the bits of a double. A range or the lines of a file are
+/// written as the list they stand for, and functions are errors.
+/// 
+/// Example:
+/// ```clarice
+/// print to_bytes ["é"]
+/// # -> [1, 5, 1, 0, 0, 0, 0, 0, 0, 0, 3, 2, 0, 0, 0, 0, 0, 0, 0, 195, 169]
+/// ```
+pub fn write(value: &Value, output: &mut Vec<u8>) -> Result<(), String> {
+    output.push(VERSION);
+    write_value(value, output)
+}
+
+fn write_value(value: &Value, output: &mut Vec<u8>) -> Result<(), String> {
+    match value {
+        Value::Void => output.push(VOID),
+        Value::Integer(i) => {
+            output.push(INTEGER);
+            output.extend_from_slice(&i.to_le_bytes());
+        }
+        Value::Double(d) => {
+            output.push(DOUBLE);
+            output.extend_from_slice(&d.to_bits().to_le_bytes());
+        }
+        Value::String(s) => {
+            output.push(STRING);
+            write_length(s.len(), output);
+            output.extend_from_slice(s.as_bytes());
+        }
+        Value::Boolean(b) => output.extend_from_slice(&[BOOLEAN, *b as u8]),
+        Value::List(elements) => {
+            output.push(LIST);
+            write_length(elements.len(), output);
+            for element in elements.iter() {
+                write_value(element, output)?;
+            }
+        }
+        Value::Map(map) => {
+            output.push(MAP);
+            write_length(map.len(), output);
+            for (key, value) in map.iter() {
+                write_value(&Value::from(key), output)?;
+                write_value(value, output)?;
+            }
+        }
+        Value::Range { .. } | Value::Lines(_) => write_value(&force_list(value)?, output)?,
+        Value::Closure(_) => return Err(format!("{} can't be turned into bytes", value)),
+    }
+    Ok(())
+}
+
+fn write_length(length: usize, output: &mut Vec<u8>) {
+    output.extend_from_slice(&(length as u64).to_le_bytes());
+}
+
+/// Decodes bytes made by `write`.
+/// 
+/// Example:
+/// ```clarice
+/// print from_bytes (to_bytes {"a": [1.5]})
+/// # -> {"a": [1.5]}
+/// ```
+pub fn read(bytes: &[u8]) -> Result<Value, String> {
+    let mut reader = Reader { bytes, position: 0, depth: 0 };
+    match reader.byte()? {
+        VERSION => (),
+        version => return Err(format!("These bytes are in version {} of the format, but only version {} can be read", version, VERSION)),
+    }
+    let value = reader.value()?;
+    if reader.position < bytes.len() {
+        return Err(format!("Invalid bytes at byte {}: there's more after the value", reader.position));
+    }
+    Ok(value)
+}
+
+/// How deeply lists and maps can nest inside each other. Reading them
+/// recurses, so this keeps deeply nested bytes from running out of stack.
+const MAX_DEPTH: usize = 500;
+
+/// Goes through encoded bytes in order.
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    depth: usize, // How many lists and maps the next value is inside
+}
+
+impl Reader<'_> {
+    fn take(&mut self, count: usize) -> Result<&[u8], String> {
+        let end = self.position.checked_add(count).filter(|&end| end <= self.bytes.len());
+        match end {
+            Some(end) => {
+                let taken = &self.bytes[self.position..end];
+                self.position = end;
+                Ok(taken)
+            }
+            None => Err("Invalid bytes: they end in the middle of a value".to_string()),
+        }
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn eight_bytes(&mut self) -> Result<[u8; 8], String> {
+        Ok(self.take(8)?.try_into().expect("exactly 8 bytes were taken"))
+    }
+
+    fn length(&mut self) -> Result<usize, String> {
+        let length = u64::from_le_bytes(self.eight_bytes()?);
+        // Every element takes at least one byte, so a longer length can't be right
+        match usize::try_from(length) {
+            Ok(length) if length <= self.bytes.len() - self.position => Ok(length),
+            _ => Err(format!("Invalid bytes at byte {}: a length of {} is longer than what's left", self.position - 8, length)),
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        let start = self.position;
+        match self.byte()? {
+            VOID => Ok(Value::Void),
+            INTEGER => Ok(Value::Integer(i64::from_le_bytes(self.eight_bytes()?))),
+            DOUBLE => Ok(Value::Double(f64::from_bits(u64::from_le_bytes(self.eight_bytes()?)))),
+            STRING => {
+                let length = self.length()?;
+                match std::str::from_utf8(self.take(length)?) {
+                    Ok(s) => Ok(Value::String(s.into())),
+                    Err(_) => Err(format!("Invalid bytes at byte {}: a string isn't valid UTF-8", start)),
+                }
+            }
+            BOOLEAN => match self.byte()? {
+                0 => Ok(Value::Boolean(false)),
+                1 => Ok(Value::Boolean(true)),
+                b => Err(format!("Invalid bytes at byte {}: {} isn't a boolean", start + 1, b)),
+            },
+            LIST | MAP if self.depth == MAX_DEPTH => Err(format!(
+                "Invalid bytes at byte {}: lists and maps can only nest {} deep",
+                start, MAX_DEPTH
+            )),
+            LIST => self.nested(Self::list),
+            MAP => self.nested(Self::map),
+            kind => Err(format!("Invalid bytes at byte {}: {} isn't a kind of value", start, kind)),
+        }
+    }
+
+    /// Reads the rest of a list or map one level deeper.
+    fn nested(&mut self, read: fn(&mut Self) -> Result<Value, String>) -> Result<Value, String> {
+        self.depth += 1;
+        let value = read(self);
+        self.depth -= 1;
+        value
+    }
+
+    fn list(&mut self) -> Result<Value, String> {
+        let length = self.length()?;
+        let elements = (0..length).map(|_| self.value()).collect::<Result<_, _>>()?;
+        Ok(Value::List(Rc::new(elements)))
+    }
+
+    fn map(&mut self) -> Result<Value, String> {
+        let length = self.length()?;
+        let mut map = Map::default();
+        for _ in 0..length {
+            let key = Key::try_from(&self.value()?)?;
+            map.insert(key, self.value()?);
+        }
+        Ok(Value::Map(Rc::new(map)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(value: &Value) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write(value, &mut bytes).unwrap();
+        bytes
+    }
+
+    fn nested() -> Value {
+        let mut map = Map::default();
+        map.insert(Key::String("nan".to_string()), Value::Double(f64::NAN));
+        map.insert(Key::Integer(-1), Value::Double(-0.0));
+        map.insert(Key::Boolean(true), Value::List(Rc::new(Vec::new())));
+        map.insert(Key::Double(1.5f64.to_bits()), Value::Void);
+        Value::List(Rc::new(vec![
+            Value::Integer(i64::MIN),
+            Value::Double(f64::INFINITY),
+            Value::String("naïve \"quoted\"\n".into()),
+            Value::Boolean(false),
+            Value::List(Rc::new(vec![Value::Integer(1), Value::List(Rc::new(vec![Value::String(String::new().into())]))])),
+            Value::Map(Rc::new(map)),
+        ]))
+    }
+
+    #[test]
+    fn values_come_back_exactly() {
+        let bytes = encode(&nested());
+        let value = read(&bytes).unwrap();
+        // NaN isn't equal to itself, so the bytes are compared instead
+        assert_eq!(encode(&value), bytes);
+        let Value::List(elements) = value else { panic!("expected a list, got {:?}", value) };
+        let Value::Map(map) = &elements[5] else { panic!("expected a map, got {:?}", elements[5]) };
+        assert!(matches!(map.get(&Key::String("nan".to_string())), Some(Value::Double(d)) if d.is_nan()));
+        assert!(matches!(map.get(&Key::Integer(-1)), Some(Value::Double(d)) if *d == 0.0 && d.is_sign_negative()));
+    }
+
+    #[test]
+    fn truncated_bytes_are_an_error() {
+        let bytes = encode(&nested());
+        for end in 0..bytes.len() {
+            let error = read(&bytes[..end]).unwrap_err();
+            assert!(error.starts_with("Invalid bytes"), "{} bytes: {}", end, error);
+        }
+    }
+
+    #[test]
+    fn deeply_nested_lists_are_an_error() {
+        // Lists of one element, each holding the next
+        let nested = |depth: usize| {
+            let mut bytes = vec![VERSION];
+            for _ in 0..depth {
+                bytes.push(LIST);
+                bytes.extend(1u64.to_le_bytes());
+            }
+            bytes.push(VOID);
+            bytes
+        };
+        assert!(read(&nested(MAX_DEPTH)).is_ok());
+        let error = format!("Invalid bytes at byte {}: lists and maps can only nest {} deep", 1 + 9 * MAX_DEPTH, MAX_DEPTH);
+        assert_eq!(read(&nested(200_000)), Err(error));
+    }
+}
@@ -0,0 +1,186 @@
+// constant_folder.rs - Constant folding for the Clarice programming language
+
+use crate::interpreter::{apply_binary_operation, Value};
+use crate::parser::{ASTNode, Expression, Statement, StatementKind};
+
+/// Replaces operations on literals with their results, e.g. `2 + 3` with `5`,
+/// so they aren't worked out again every time they run. Anything that
+/// depends on a variable is left alone.
+///
+/// Errors such as `1 / 0` are reported here, before the program runs.
+pub fn fold_constants(program: &mut ASTNode) -> Result<(), String> {
+    match program {
+        ASTNode::Program(statements) => fold_block(statements),
+    }
+}
+
+fn fold_block(statements: &mut [Statement]) -> Result<(), String> {
+    for statement in statements {
+        fold_statement(statement)?;
+    }
+    Ok(())
+}
+
+fn fold_statement(statement: &mut Statement) -> Result<(), String> {
+    match &mut statement.kind {
+        StatementKind::With(with_statement) => {
+            fold_expression(&mut with_statement.expression)?;
+            match &mut with_statement.body {
+                Some(body) => fold_statement(body),
+                None => Ok(()),
+            }
+        }
+        StatementKind::Set(set_statement) => {
+            for index in &mut set_statement.indexes {
+                fold_expression(index)?;
+            }
+            fold_expression(&mut set_statement.expression)
+        }
+        StatementKind::Destructure(destructure_statement) => fold_expression(&mut destructure_statement.expression),
+        StatementKind::As(as_statement) => fold_expression(&mut as_statement.expression),
+        StatementKind::To(to_statement) => fold_expression(&mut to_statement.expression),
+        StatementKind::Then(then_statement) => fold_statement(&mut then_statement.statement),
+        StatementKind::Do(do_statement) => {
+            fold_expression(&mut do_statement.expression)?;
+            fold_statement(&mut do_statement.statement)
+        }
+        StatementKind::Print(print_statement) => fold_expression(&mut print_statement.expression),
+        StatementKind::Where(where_statement) => {
+            for condition in &mut where_statement.conditions {
+                fold_expression(condition)?;
+            }
+            fold_block(&mut where_statement.true_branch)?;
+            match &mut where_statement.false_branch {
+                Some(false_branch) => fold_block(false_branch),
+                None => Ok(()),
+            }
+        }
+        StatementKind::Loop(loop_statement) => fold_block(&mut loop_statement.body),
+        StatementKind::While(while_statement) => {
+            fold_expression(&mut while_statement.condition)?;
+            fold_block(&mut while_statement.body)
+        }
+        StatementKind::Iter(iter_statement) => {
+            fold_expression(&mut iter_statement.iterable)?;
+            fold_block(&mut iter_statement.body)
+        }
+        StatementKind::Exit(exit_statement) => match &mut exit_statement.code {
+            Some(code) => fold_expression(code),
+            None => Ok(()),
+        },
+        StatementKind::Match(match_statement) => {
+            fold_expression(&mut match_statement.scrutinee)?;
+            for (pattern, body) in &mut match_statement.cases {
+                fold_expression(pattern)?;
+                fold_block(body)?;
+            }
+            match &mut match_statement.default {
+                Some(default) => fold_block(default),
+                None => Ok(()),
+            }
+        }
+        StatementKind::Function(function_statement) => fold_block(&mut function_statement.body),
+        StatementKind::Break(_) | StatementKind::Next | StatementKind::Pass | StatementKind::Comment(..) | StatementKind::Import(_) => Ok(()),
+        StatementKind::Expression(expression) => fold_expression(expression),
+        StatementKind::Error => unreachable!("a program with parse errors is never folded"),
+    }
+}
+
+fn fold_expression(expression: &mut Expression) -> Result<(), String> {
+    match expression {
+        Expression::ListLiteral(elements) => {
+            for element in elements {
+                fold_expression(element)?;
+            }
+        }
+        Expression::MapLiteral(entries) => {
+            for (key, value) in entries {
+                fold_expression(key)?;
+                fold_expression(value)?;
+            }
+        }
+        Expression::Comprehension(comprehension) => {
+            fold_expression(&mut comprehension.element)?;
+            fold_expression(&mut comprehension.iterable)?;
+            if let Some(condition) = &mut comprehension.condition {
+                fold_expression(condition)?;
+            }
+        }
+        Expression::FunctionCall(_, arguments) => {
+            for argument in arguments {
+                fold_expression(argument)?;
+            }
+        }
+        Expression::Block(statements) => fold_block(statements)?,
+        Expression::Lambda(function_statement) => fold_block(&mut function_statement.body)?,
+        Expression::Cast(expression, _) => fold_expression(expression)?,
+        Expression::Index(expression, index) => {
+            fold_expression(expression)?;
+            fold_expression(index)?;
+        }
+        Expression::Conditional(condition, true_branch, false_branch) => {
+            fold_expression(condition)?;
+            // A branch that can never be taken is dropped without being folded,
+            // so an error in it isn't reported
+            if let Expression::BooleanLiteral(holds) = **condition {
+                let mut taken = if holds { true_branch.clone() } else { false_branch.clone() };
+                fold_expression(&mut taken)?;
+                *expression = *taken;
+                return Ok(());
+            }
+            fold_expression(true_branch)?;
+            fold_expression(false_branch)?;
+        }
+        Expression::BinaryOperation(..) => {
+            // A chain like `1 + 2 + 3` groups to the left, so it's taken apart and
+            // folded from its innermost operand outwards rather than recursing
+            // once per operator
+            let mut operations = Vec::new();
+            let mut left = std::mem::replace(expression, Expression::BooleanLiteral(false));
+            while let Some((inner, operator, right)) = left.take_operation() {
+                operations.push((operator, right));
+                left = inner;
+            }
+            fold_expression(&mut left)?;
+            for (operator, mut right) in operations.into_iter().rev() {
+                fold_expression(&mut right)?;
+                left = fold_operation(left, operator, right)?;
+            }
+            *expression = left;
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+/// An operation on two folded operands, worked out if they're both literals.
+fn fold_operation(left: Expression, operator: String, right: Expression) -> Result<Expression, String> {
+    if let (Some(left), Some(right)) = (literal_value(&left), literal_value(&right)) {
+        let value = apply_binary_operation(&left, &operator, &right)?;
+        if let Some(folded) = value_literal(value) {
+            return Ok(folded);
+        }
+    }
+    Ok(Expression::BinaryOperation(Box::new(left), operator, Box::new(right)))
+}
+
+/// The value of a literal expression, or `None` if it isn't a literal.
+fn literal_value(expression: &Expression) -> Option<Value> {
+    match expression {
+        Expression::IntegerLiteral(i) => Some(Value::Integer(*i)),
+        Expression::DoubleLiteral(d) => Some(Value::Double(*d)),
+        Expression::StringLiteral(s) => Some(Value::String(s.as_str().into())),
+        Expression::BooleanLiteral(b) => Some(Value::Boolean(*b)),
+        _ => None,
+    }
+}
+
+fn value_literal(value: Value) -> Option<Expression> {
+    match value {
+        Value::Integer(i) => Some(Expression::IntegerLiteral(i)),
+        Value::Double(d) => Some(Expression::DoubleLiteral(d)),
+        Value::String(s) => Some(Expression::StringLiteral(s.to_string())),
+        Value::Boolean(b) => Some(Expression::BooleanLiteral(b)),
+        _ => None,
+    }
+}
@@ -0,0 +1,62 @@
+// error.rs - Errors for the Clarice programming language
+
+use std::fmt;
+
+/// An error found in a Clarice program before it runs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClariceError {
+    pub message: String,
+    pub line: Option<usize>,
+}
+
+impl ClariceError {
+    pub fn new(message: String) -> Self {
+        ClariceError { message, line: None }
+    }
+
+    pub fn at_line(message: String, line: usize) -> Self {
+        ClariceError { message, line: Some(line) }
+    }
+}
+
+impl fmt::Display for ClariceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "line {}: {}", line, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+/// An error that stops a Clarice program while it runs, such as dividing by
+/// zero or calling a function with the wrong arguments. An `exit` statement
+/// stops a program the same way, so that it gets out of any loops and
+/// functions it's in, but with a status code instead of a message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError {
+    pub message: String,
+    pub exit_code: Option<i32>,
+}
+
+impl RuntimeError {
+    pub fn new(message: String) -> Self {
+        RuntimeError { message, exit_code: None }
+    }
+
+    /// Stops the program with a status code, for `exit`.
+    pub fn exit(code: i32) -> Self {
+        RuntimeError { message: format!("Exited with status code {}", code), exit_code: Some(code) }
+    }
+}
+
+impl From<String> for RuntimeError {
+    fn from(message: String) -> Self {
+        RuntimeError::new(message)
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
@@ -0,0 +1,413 @@
+// formatter.rs - Source formatter for the Clarice programming language
+
+use crate::error::ClariceError;
+use crate::interpreter::format_double;
+use crate::lexer::Lexer;
+use crate::parser::*;
+use crate::symbol_table::Type;
+
+const INDENT: &str = "    ";
+
+/// Parses a program and renders it back to source with `format`, keeping its
+/// comments. A comment goes before the statement that followed it, or at
+/// the end of the line if it came after some code. Comments in the middle
+/// of a statement that spans lines move to after it.
+pub fn format_source(source: &str) -> Result<String, Vec<ClariceError>> {
+    let program = Parser::new(Lexer::with_comments(source)).parse_only()?;
+    Ok(format(&program))
+}
+
+/// Renders a program back to source in the canonical layout: one statement
+/// per line, block bodies indented by four spaces, and single spaces around
+/// operators. A `with` stays on the same line as the statement it covers.
+pub fn format(program: &ASTNode) -> String {
+    let ASTNode::Program(statements) = program;
+    let mut output = String::new();
+    format_block(&mut output, statements, 0);
+    output
+}
+
+fn format_block(output: &mut String, statements: &[Statement], depth: usize) {
+    for statement in statements {
+        // A comment that came after some code stays at the end of that code's line
+        if let StatementKind::Comment(text, true) = &statement.kind {
+            if output.ends_with('\n') {
+                output.pop();
+                output.push_str(&format!(" #{}\n", text));
+                continue;
+            }
+        }
+        output.push_str(&INDENT.repeat(depth));
+        format_statement(output, statement, depth);
+        output.push('\n');
+    }
+}
+
+/// Writes a block opened by `do` and the statements inside it, leaving the
+/// `end` (or `otherwise` or `case`) that closes it to the caller.
+fn format_do_block(output: &mut String, statements: &[Statement], depth: usize) {
+    output.push_str("do\n");
+    format_block(output, statements, depth + 1);
+    output.push_str(&INDENT.repeat(depth));
+}
+
+fn format_statement(output: &mut String, statement: &Statement, depth: usize) {
+    match &statement.kind {
+        StatementKind::With(with_statement) => {
+            output.push_str(&format!("with {} as ", with_statement.identifier));
+            format_expression(output, &with_statement.expression, depth);
+            // The statement a `with` covers is kept on the same line
+            if let Some(body) = &with_statement.body {
+                output.push(' ');
+                format_statement(output, body, depth);
+            }
+        }
+        StatementKind::Set(set_statement) => {
+            output.push_str(&format!("set {}", set_statement.variable));
+            for index in &set_statement.indexes {
+                output.push('[');
+                format_expression(output, index, depth);
+                output.push(']');
+            }
+            output.push_str(" to ");
+            format_expression(output, &set_statement.expression, depth);
+        }
+        StatementKind::Destructure(destructure_statement) => {
+            output.push_str(&format!("set [{}] to ", destructure_statement.variables.join(", ")));
+            format_expression(output, &destructure_statement.expression, depth);
+        }
+        StatementKind::As(as_statement) => {
+            output.push_str(&format!("as {} ", as_statement.identifier));
+            format_expression(output, &as_statement.expression, depth);
+        }
+        StatementKind::To(to_statement) => {
+            output.push_str(&format!("to {} ", to_statement.identifier));
+            format_expression(output, &to_statement.expression, depth);
+        }
+        StatementKind::Then(then_statement) => {
+            output.push_str("then ");
+            format_statement(output, &then_statement.statement, depth);
+        }
+        StatementKind::Do(do_statement) => {
+            output.push_str("do ");
+            format_expression(output, &do_statement.expression, depth);
+            output.push(' ');
+            format_statement(output, &do_statement.statement, depth);
+        }
+        StatementKind::Print(print_statement) => {
+            output.push_str("print ");
+            format_expression(output, &print_statement.expression, depth);
+        }
+        StatementKind::Where(where_statement) => {
+            output.push_str("where ");
+            for (i, condition) in where_statement.conditions.iter().enumerate() {
+                if i > 0 {
+                    output.push_str(", ");
+                }
+                format_expression(output, condition, depth);
+            }
+            output.push(' ');
+            format_do_block(output, &where_statement.true_branch, depth);
+            if let Some(false_branch) = &where_statement.false_branch {
+                output.push_str("otherwise ");
+                format_do_block(output, false_branch, depth);
+            }
+            output.push_str("end");
+        }
+        StatementKind::Loop(loop_statement) => {
+            output.push_str("loop ");
+            if let Some(label) = &loop_statement.label {
+                output.push_str(&format!("as {} ", label));
+            }
+            format_do_block(output, &loop_statement.body, depth);
+            output.push_str("end");
+        }
+        StatementKind::While(while_statement) => {
+            output.push_str("while ");
+            format_expression(output, &while_statement.condition, depth);
+            output.push(' ');
+            format_do_block(output, &while_statement.body, depth);
+            output.push_str("end");
+        }
+        StatementKind::Iter(iter_statement) => {
+            output.push_str(&format!("iter {}", iter_statement.variable));
+            if let Some(value_variable) = &iter_statement.value_variable {
+                output.push_str(&format!(", {}", value_variable));
+            }
+            output.push_str(" in ");
+            format_expression(output, &iter_statement.iterable, depth);
+            output.push(' ');
+            format_do_block(output, &iter_statement.body, depth);
+            output.push_str("end");
+        }
+        StatementKind::Exit(exit_statement) => {
+            output.push_str("exit");
+            if let Some(code) = &exit_statement.code {
+                output.push(' ');
+                format_expression(output, code, depth);
+            }
+        }
+        StatementKind::Match(match_statement) => {
+            output.push_str("match ");
+            format_expression(output, &match_statement.scrutinee, depth);
+            output.push_str(" do\n");
+            for (pattern, body) in &match_statement.cases {
+                output.push_str(&INDENT.repeat(depth + 1));
+                output.push_str("case ");
+                format_expression(output, pattern, depth + 1);
+                // Each case runs up to the next one, so only the match itself has an `end`
+                output.push_str(" do\n");
+                format_block(output, body, depth + 2);
+            }
+            if let Some(default) = &match_statement.default {
+                output.push_str(&INDENT.repeat(depth + 1));
+                output.push_str("otherwise do\n");
+                format_block(output, default, depth + 2);
+            }
+            output.push_str(&INDENT.repeat(depth));
+            output.push_str("end");
+        }
+        StatementKind::Function(function_statement) => {
+            output.push_str(&format!("fn {}", function_statement.name));
+            format_function(output, function_statement, depth, false);
+        }
+        StatementKind::Break(None) => output.push_str("break"),
+        StatementKind::Break(Some(label)) => output.push_str(&format!("break {}", label)),
+        StatementKind::Next => output.push_str("next"),
+        StatementKind::Pass => output.push_str("pass"),
+        StatementKind::Comment(text, _) => output.push_str(&format!("#{}", text)),
+        StatementKind::Import(import_statement) => {
+            output.push_str(&format!("import \"{}\"", import_statement.path));
+            if let Some(alias) = &import_statement.alias {
+                output.push_str(&format!(" as {}", alias));
+            }
+        }
+        StatementKind::Expression(expression) => format_expression(output, expression, depth),
+        StatementKind::Error => unreachable!("a program with parse errors is never formatted"),
+    }
+}
+
+/// Writes a function from its parameters onwards, which is the same for a
+/// named function and a lambda, except that a lambda that's just one
+/// expression is kept on one line.
+fn format_function(output: &mut String, function_statement: &FunctionStatement, depth: usize, inline: bool) {
+    let parameters: Vec<String> = function_statement.parameters.iter()
+        .zip(&function_statement.parameter_types)
+        .map(|(parameter, parameter_type)| match parameter_type {
+            Some(parameter_type) => format!("{}: {}", parameter, type_name(parameter_type)),
+            None => parameter.clone(),
+        })
+        .collect();
+    output.push_str(&format!("({})", parameters.join(", ")));
+    if let Some(return_type) = &function_statement.return_type {
+        output.push_str(&format!(": {}", type_name(return_type)));
+    }
+    output.push(' ');
+    match function_statement.body.as_slice() {
+        [Statement { kind: StatementKind::Expression(expression), .. }] if inline => {
+            output.push_str("do ");
+            format_expression(output, expression, depth);
+            output.push(' ');
+        }
+        body => format_do_block(output, body, depth),
+    }
+    output.push_str("end");
+}
+
+/// How a type is written in a type annotation.
+fn type_name(annotation: &Type) -> String {
+    match annotation {
+        Type::Integer => "integer".to_string(),
+        Type::Double => "double".to_string(),
+        Type::String => "string".to_string(),
+        Type::Boolean => "boolean".to_string(),
+        Type::List(element_type) => format!("[{}]", type_name(element_type)),
+        Type::Map(key_type, value_type) => format!("{{{}: {}}}", type_name(key_type), type_name(value_type)),
+        // Annotations can't name the others
+        _ => "any".to_string(),
+    }
+}
+
+/// How tightly an expression binds, so that brackets are only added where
+/// they're needed.
+fn precedence(expression: &Expression) -> u8 {
+    match expression {
+        Expression::Conditional(_, _, _) => 0,
+        Expression::BinaryOperation(_, operator, _) => match operator.as_str() {
+            "or" => 1,
+            operator if operator == "in" || COMPARISONS.contains(&operator) => 2,
+            ".." => 3,
+            "+" | "-" => 4,
+            _ => 5,
+        },
+        Expression::Cast(_, _) => 6,
+        Expression::FunctionCall(_, _) | Expression::Block(_) | Expression::Lambda(_) => 7,
+        _ => 8,
+    }
+}
+
+/// Writes an expression, wrapped in brackets if it binds more loosely than
+/// `minimum` allows.
+fn format_operand(output: &mut String, expression: &Expression, minimum: u8, depth: usize) {
+    if precedence(expression) < minimum {
+        output.push('(');
+        format_expression(output, expression, depth);
+        output.push(')');
+    }
+    else {
+        format_expression(output, expression, depth);
+    }
+}
+
+fn format_expression(output: &mut String, expression: &Expression, depth: usize) {
+    match expression {
+        Expression::Identifier(name) => output.push_str(name),
+        Expression::IntegerLiteral(i) => output.push_str(&i.to_string()),
+        Expression::DoubleLiteral(d) => output.push_str(&format_double(*d)),
+        Expression::StringLiteral(s) => {
+            output.push('"');
+            for c in s.chars() {
+                match c {
+                    '\n' => output.push_str("\\n"),
+                    '\t' => output.push_str("\\t"),
+                    '\\' | '"' => {
+                        output.push('\\');
+                        output.push(c);
+                    }
+                    _ => output.push(c),
+                }
+            }
+            output.push('"');
+        }
+        Expression::BooleanLiteral(b) => output.push_str(&b.to_string()),
+        Expression::ListLiteral(elements) => {
+            output.push('[');
+            for (i, element) in elements.iter().enumerate() {
+                if i > 0 {
+                    output.push_str(", ");
+                }
+                format_expression(output, element, depth);
+            }
+            output.push(']');
+        }
+        Expression::Comprehension(comprehension) => {
+            output.push('[');
+            format_expression(output, &comprehension.element, depth);
+            output.push_str(&format!(" for {} in ", comprehension.variable));
+            format_expression(output, &comprehension.iterable, depth);
+            if let Some(condition) = &comprehension.condition {
+                output.push_str(" where ");
+                format_expression(output, condition, depth);
+            }
+            output.push(']');
+        }
+        Expression::Error => unreachable!("a program with parse errors is never formatted"),
+        Expression::MapLiteral(entries) => {
+            output.push('{');
+            for (i, (key, value)) in entries.iter().enumerate() {
+                if i > 0 {
+                    output.push_str(", ");
+                }
+                format_expression(output, key, depth);
+                output.push_str(": ");
+                format_expression(output, value, depth);
+            }
+            output.push('}');
+        }
+        Expression::FunctionCall(name, arguments) => {
+            output.push_str(name);
+            for argument in arguments {
+                output.push(' ');
+                // Arguments are atoms, so anything bigger needs brackets,
+                // including calls, blocks and lambdas
+                format_operand(output, argument, 8, depth);
+            }
+        }
+        Expression::BinaryOperation(left, operator, right) => {
+            let level = precedence(expression);
+            // `in` and comparisons don't chain, and the other operators group to the left
+            let left_minimum = if operator == "in" || COMPARISONS.contains(&operator.as_str()) { level + 1 } else { level };
+            format_operand(output, left, left_minimum, depth);
+            output.push_str(&format!(" {} ", operator));
+            format_operand(output, right, level + 1, depth);
+        }
+        Expression::Block(statements) => {
+            format_do_block(output, statements, depth);
+            output.push_str("end");
+        }
+        Expression::Lambda(function_statement) => {
+            output.push_str("fn");
+            format_function(output, function_statement, depth, true);
+        }
+        Expression::Index(expression, index) => {
+            format_operand(output, expression, 8, depth);
+            output.push('[');
+            format_expression(output, index, depth);
+            output.push(']');
+        }
+        Expression::Cast(expression, target) => {
+            // Casts chain to the left, e.g. `x as double as string`
+            format_operand(output, expression, 6, depth);
+            output.push_str(&format!(" as {}", type_name(target)));
+        }
+        Expression::Conditional(condition, true_branch, false_branch) => {
+            output.push_str("if ");
+            format_expression(output, condition, depth);
+            output.push_str(" then ");
+            format_expression(output, true_branch, depth);
+            output.push_str(" else ");
+            format_expression(output, false_branch, depth);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formatting_twice_changes_nothing_more() {
+        let source = "
+            # Sums and labels
+            set total to 0   # starts at nothing
+            set ages to {\"Ada\": 36}
+            set ages[\"Alan\"] to 41
+            iter name, age in ages do
+                print name + \" is \" + str age
+            end
+            loop as outer do
+                loop do
+                    where total in [0], 1 in [1] then break outer
+                    otherwise do next end
+                end
+            end
+            fn twice(x: integer): integer do x * 2 end # doubles
+            print str (twice 2)
+            print \"n is \" .. (1 .. 2) .. 3 + 4 as string
+            print (1 == 2) != (3 + 1 <= 4)
+            set doubled to map (fn(x) do x * 2 end) [1, 2.5 as integer]
+            set evens to [x for x in 10 where x in [0, 2, 4]]
+            match 2 do
+                case 1 then print \"one\"
+                otherwise then print if 1 in [1] then \"a\\tb\" else (get_env \"HOME\" or \"none\")
+            end
+            with x as 1 print x
+            # The end
+        ";
+        let formatted = format_source(source).unwrap();
+        assert_eq!(format_source(&formatted).unwrap(), formatted);
+    }
+
+    #[test]
+    fn comments_are_kept() {
+        let source = "\
+# Before everything
+set x to 1 # after some code
+where x in [1] do # after `do`
+    # on its own line
+    print x
+end
+";
+        assert_eq!(format_source(source).unwrap(), source);
+    }
+}
@@ -1,30 +1,808 @@
 // interpreter.rs - The Clarice Interpreter
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io::{BufRead, Read, Write};
+use std::path::PathBuf;
+use std::rc::Rc;
 
+use crate::error::RuntimeError;
+use crate::binary;
+use crate::json;
 use crate::parser::*;
-use crate::symbol_table::{SymbolTable, Type};
+use crate::symbol_table::Type;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Value {
     Integer(i64),
     Double(f64),
-    String(String),
+    String(Rc<str>), // Shared, so that interned strings don't need copying
     Boolean(bool),
-    List(Vec<Expression>),
-    Closure(String, Vec<Expression>),
+    List(Rc<Vec<Value>>), // Shared too, and only copied when it changes
+    Map(Rc<Map>),
+    /// The integers from `start` up to (but not including) `end`, counting
+    /// by `step`. They're only worked out as they're needed, so a range can
+    /// be as long as you like.
+    Range { start: i64, end: i64, step: i64 },
+    /// The lines of the file at this path. Like a range, they're only read
+    /// as they're needed, so `iter` can go through a file of any size.
+    Lines(Rc<str>),
+    Closure(Rc<Closure>),
     Void,
 }
 
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write(f, false)
+    }
+}
+
+/// Displays a value with its booleans as `yes` and `no`.
+struct YesNo<'a>(&'a Value);
+
+impl fmt::Display for YesNo<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.write(f, true)
+    }
+}
+
+impl Value {
+    /// Writes a value for `Display`, with booleans as `yes` and `no` rather
+    /// than `true` and `false` if `yes_no` is on.
+    fn write(&self, f: &mut fmt::Formatter, yes_no: bool) -> fmt::Result {
+        match self {
+            Value::String(s) => write!(f, "{}", s),
+            Value::Integer(i) => write!(f, "{}", i),
+            Value::Double(d) => write!(f, "{}", format_double(*d)),
+            Value::Boolean(true) if yes_no => write!(f, "yes"),
+            Value::Boolean(false) if yes_no => write!(f, "no"),
+            Value::Boolean(b) => write!(f, "{}", b),
+            Value::List(l) => {
+                write!(f, "[")?;
+                for (i, value) in l.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    value.write_element(f, yes_no)?;
+                }
+                write!(f, "]")
+            }
+            Value::Map(map) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in map.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    Value::from(key).write_element(f, yes_no)?;
+                    write!(f, ": ")?;
+                    value.write_element(f, yes_no)?;
+                }
+                write!(f, "}}")
+            }
+            Value::Range { start, end, step: 1 } => write!(f, "range {} {}", start, end),
+            Value::Range { start, end, step } => write!(f, "range_by {} {} {}", start, end, step),
+            Value::Lines(path) => write!(f, "lines_of {:?}", path),
+            Value::Closure(closure) => write!(f, "<function {}>", closure.name),
+            Value::Void => Ok(()),
+        }
+    }
+
+    /// Writes a value inside a list or map, where strings are quoted and void
+    /// is written as `void` so that it doesn't disappear.
+    fn write_element(&self, f: &mut fmt::Formatter, yes_no: bool) -> fmt::Result {
+        match self {
+            Value::String(s) => write!(f, "{:?}", s),
+            Value::Void => write!(f, "void"),
+            _ => self.write(f, yes_no),
+        }
+    }
+}
+
+/// A function, along with the variables it could see where it was defined.
+#[derive(Debug, PartialEq)]
+pub struct Closure {
+    pub name: String,
+    pub parameters: Vec<String>,
+    pub body: Vec<Statement>,
+    pub captured: HashMap<String, Value>,
+}
+
+/// How the body of a function finished: with a value, or by calling the
+/// function itself again with these arguments.
+enum TailResult {
+    Value(Value),
+    Call(Vec<Value>),
+}
+
+/// Something left to do while running a block. Blocks inside blocks go on a
+/// stack of these instead of calling back into `execute_statement`, so
+/// running deeply nested blocks doesn't run out of stack. Loops, function
+/// calls and expressions still nest the usual way.
+enum Work<'a> {
+    Statement(&'a Statement),
+    Discard, // Makes the statement that ran last give a void, as `then` and `do` do
+    Unbind { name: &'a String, previous: Option<Value> }, // Drops a `with` variable, or puts back what it hid
+}
+
+/// Puts the statements of a block on a work stack, first on top.
+fn push_block<'a>(work: &mut Vec<Work<'a>>, statements: &'a [Statement]) {
+    work.extend(statements.iter().rev().map(Work::Statement));
+}
+
+/// The variables of an `Environment` at some point, which it can be put back
+/// to with `restore`.
+pub struct EnvSnapshot {
+    variables: HashMap<String, Value>,
+}
+
+/// Why running part of a program stopped before reaching its end.
+enum Interrupt {
+    Error(RuntimeError),
+    Break(Option<String>), // A `break` on its way out to the loop it's leaving, which might be named
+    Next, // A `next` on its way out to the loop whose iteration it's ending
+}
+
+impl From<RuntimeError> for Interrupt {
+    fn from(error: RuntimeError) -> Self {
+        Interrupt::Error(error)
+    }
+}
+
+impl From<String> for Interrupt {
+    fn from(message: String) -> Self {
+        Interrupt::Error(RuntimeError::new(message))
+    }
+}
+
+/// Turns an interrupt that reached the top of a program or function into an
+/// error, as there's no loop left for a `break` to leave.
+fn outside_loop(interrupt: Interrupt) -> RuntimeError {
+    match interrupt {
+        Interrupt::Error(error) => error,
+        Interrupt::Break(None) => RuntimeError::new("`break` can only be used inside a loop".to_string()),
+        Interrupt::Break(Some(label)) => RuntimeError::new(format!("There's no loop called `{}` for `break` to leave", label)),
+        Interrupt::Next => RuntimeError::new("`next` can only be used inside a loop".to_string()),
+    }
+}
+
+/// A value that can be used as a key in a map or set. Only integers, strings,
+/// booleans and doubles (other than NaN) can be keys.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Key {
+    Integer(i64),
+    String(String),
+    Boolean(bool),
+    Double(u64), // The bits of the double, with `-0.0` stored as `0.0`
+}
+
+impl TryFrom<&Value> for Key {
+    type Error = String;
+
+    fn try_from(value: &Value) -> Result<Self, Self::Error> {
+        match value {
+            Value::Integer(i) => Ok(Key::Integer(*i)),
+            Value::String(s) => Ok(Key::String(s.to_string())),
+            Value::Boolean(b) => Ok(Key::Boolean(*b)),
+            Value::Double(d) if d.is_nan() => Err("NaN cannot be used as a key".to_string()),
+            // Adding 0.0 turns -0.0 into 0.0, as they're equal
+            Value::Double(d) => Ok(Key::Double((d + 0.0).to_bits())),
+            _ => Err(format!("{:?} cannot be used as a key - only integers, doubles, strings and booleans can", value)),
+        }
+    }
+}
+
+impl From<&Key> for Value {
+    fn from(key: &Key) -> Self {
+        match key {
+            Key::Integer(i) => Value::Integer(*i),
+            Key::String(s) => Value::String(s.as_str().into()),
+            Key::Boolean(b) => Value::Boolean(*b),
+            Key::Double(bits) => Value::Double(f64::from_bits(*bits)),
+        }
+    }
+}
+
+/// Keys and their values, in the order the keys were first added.
+#[derive(Debug, Clone, Default)]
+pub struct Map {
+    entries: Vec<(Key, Value)>,
+    indices: HashMap<Key, usize>, // Where each key is in `entries`
+}
+
+impl Map {
+    pub fn get(&self, key: &Key) -> Option<&Value> {
+        self.indices.get(key).map(|&index| &self.entries[index].1)
+    }
+
+    pub fn get_mut(&mut self, key: &Key) -> Option<&mut Value> {
+        self.indices.get(key).map(|&index| &mut self.entries[index].1)
+    }
+
+    /// Sets the value for a key, which keeps its place if it's already there.
+    pub fn insert(&mut self, key: Key, value: Value) {
+        match self.indices.get(&key) {
+            Some(&index) => self.entries[index].1 = value,
+            None => {
+                self.indices.insert(key.clone(), self.entries.len());
+                self.entries.push((key, value));
+            }
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Key, &Value)> {
+        self.entries.iter().map(|(key, value)| (key, value))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// Two maps are equal if they have the same keys with the same values, in
+/// any order.
+impl PartialEq for Map {
+    fn eq(&self, other: &Self) -> bool {
+        self.entries.len() == other.entries.len()
+            && self.iter().all(|(key, value)| other.get(key) == Some(value))
+    }
+}
+
+/// Formats a double so that it always reads back as the same double.
+/// 
+/// Rust's float formatting is locale-independent (always `.` as the decimal
+/// separator) and gives the shortest representation that round-trips, but it
+/// drops the fractional part of whole numbers, so `3.0` gets its `.0` back.
+/// 
+/// Dividing by a double zero gives infinity rather than an error, as does
+/// anything too big for a double, and `0.0 / 0.0` gives "not a number".
+/// These print as `inf`, `-inf` and `nan`, the same way `double` reads them.
+/// `nan` isn't equal to anything, not even itself, so `==` is false for it
+/// and `in` and `match` never find it. It isn't less or greater than anything
+/// either.
+/// 
+/// Example:
+/// ```clarice
+/// print 1.0 / 0.0
+/// # -> inf
+/// print (0.0 / 0.0) in [double "nan"]
+/// # -> false
+/// ```
+pub fn format_double(d: f64) -> String {
+    if d.is_nan() {
+        return "nan".to_string();
+    }
+    let formatted = d.to_string();
+    if d.is_finite() && !formatted.contains('.') {
+        format!("{}.0", formatted)
+    }
+    else {
+        formatted
+    }
+}
+
+/// Widens an integer to a double, for arithmetic on mixed numbers.
+fn as_double(value: &Value) -> f64 {
+    match value {
+        Value::Integer(i) => *i as f64,
+        Value::Double(d) => *d,
+        _ => f64::NAN,
+    }
+}
+
+/// Whether two doubles are equal to within rounding error. The tolerance is
+/// relative to the size of the numbers, so it works for big and small ones
+/// alike.
+/// 
+/// Example:
+/// ```clarice
+/// print almost_equal (0.1 + 0.2) 0.3
+/// # -> true
+/// ```
+fn almost_equal(a: f64, b: f64) -> bool {
+    const EPSILON: f64 = 1e-9;
+    a == b || (a - b).abs() <= EPSILON * a.abs().max(b.abs()).max(1.0)
+}
+
+/// The variables every program starts with. `version` is the version of
+/// Clarice running it, e.g. `"0.1.0"`.
+fn constants() -> HashMap<String, Value> {
+    HashMap::from([
+        ("pi".to_string(), Value::Double(std::f64::consts::PI)),
+        ("e".to_string(), Value::Double(std::f64::consts::E)),
+        ("version".to_string(), Value::String(env!("CARGO_PKG_VERSION").into())),
+    ])
+}
+
+/// The element at an index of a list, range or string, counting from 0, or
+/// the value for a key in a map. A negative index counts back from the end,
+/// so -1 is the last element. A key that isn't in the map is an error.
+/// 
+/// Example:
+/// ```clarice
+/// set xs to [10, 20, 30]
+/// print xs[0]
+/// # -> 10
+/// print xs[0 - 1]
+/// # -> 30
+/// print "hello"[1]
+/// # -> e
+/// print {"a": 1, "b": 2}["b"]
+/// # -> 2
+/// ```
+fn index_into(value: &Value, index: &Value) -> Result<Value, String> {
+    let length = match value {
+        Value::Map(map) => {
+            return match map.get(&Key::try_from(index)?) {
+                Some(value) => Ok(value.clone()),
+                None => Err(format!("The map has no key {:?}", index)),
+            }
+        }
+        Value::List(elements) => elements.len() as i128,
+        Value::String(s) => s.chars().count() as i128,
+        Value::Range { start, end, step } => range_length(*start, *end, *step),
+        Value::Lines(_) => return index_into(&force_list(value)?, index),
+        _ => return Err(format!("Cannot index into {:?}", value)),
+    };
+    let kind = if matches!(value, Value::String(_)) { "string" } else { "list" };
+    let position = position(index, length, kind)?;
+    Ok(match value {
+        Value::List(elements) => elements[position].clone(),
+        Value::String(s) => Value::String(s.chars().nth(position).unwrap_or_default().to_string().into()),
+        Value::Range { start, step, .. } => Value::Integer((*start as i128 + position as i128 * *step as i128) as i64),
+        _ => unreachable!("only lists, strings and ranges have a length"),
+    })
+}
+
+/// Where an index points in a list (or string) of some length, checking
+/// that it's inside it.
+fn position(index: &Value, length: i128, kind: &str) -> Result<usize, String> {
+    let Value::Integer(index) = *index else {
+        return Err(format!("An index has to be an integer, got {:?}", index));
+    };
+    let position = if index < 0 { length + index as i128 } else { index as i128 };
+    if !(0..length).contains(&position) {
+        return Err(format!("index {} out of bounds for {} of length {}", index, kind, length));
+    }
+    // The position is now known to fit, as it's less than the length
+    Ok(position as usize)
+}
+
+/// Changes the element at an index of a list, or the value for a key in a
+/// map, for `set xs[i] to value`. With more than one index, the earlier ones
+/// find the list or map to change, as in `set grid[0][1] to value`. A list
+/// or map shared with another variable is copied first, so that only this
+/// one changes.
+fn set_index(target: &mut Value, indexes: &[Value], value: Value) -> Result<(), String> {
+    let Some((index, rest)) = indexes.split_first() else {
+        *target = value;
+        return Ok(());
+    };
+    match target {
+        Value::List(elements) => {
+            let position = position(index, elements.len() as i128, "list")?;
+            set_index(&mut Rc::make_mut(elements)[position], rest, value)
+        }
+        Value::Map(map) => {
+            let key = Key::try_from(index)?;
+            let map = Rc::make_mut(map);
+            if rest.is_empty() {
+                map.insert(key, value);
+                return Ok(());
+            }
+            match map.get_mut(&key) {
+                Some(inner) => set_index(inner, rest, value),
+                None => Err(format!("The map has no key {:?}", index)),
+            }
+        }
+        Value::Range { .. } | Value::Lines(_) => {
+            *target = force_list(target)?;
+            set_index(target, indexes, value)
+        }
+        Value::String(_) => Err("A string can't be changed a character at a time - make a new string instead".to_string()),
+        _ => Err(format!("Cannot index into {:?}", target)),
+    }
+}
+
+/// Applies one of the maths functions, like `sqrt`, to a number.
+fn math(name: &str, x: f64) -> Result<f64, String> {
+    match name {
+        "sqrt" if x < 0.0 => Err(format!("Cannot take the square root of {}, as it's negative", format_double(x))),
+        "log" if x <= 0.0 => Err(format!("Cannot take the logarithm of {}, as it isn't above 0", format_double(x))),
+        "sqrt" => Ok(x.sqrt()),
+        "sin" => Ok(x.sin()),
+        "cos" => Ok(x.cos()),
+        "tan" => Ok(x.tan()),
+        "log" => Ok(x.ln()),
+        "exp" => Ok(x.exp()),
+        _ => Err(format!("No function `{}`", name)),
+    }
+}
+
+/// Orders two numbers or two strings, for sorting and `compare`. Integers and doubles can
+/// be compared with each other.
+fn compare(a: &Value, b: &Value) -> Result<std::cmp::Ordering, String> {
+    match (a, b) {
+        (Value::Integer(a), Value::Integer(b)) => Ok(a.cmp(b)),
+        (Value::Integer(_) | Value::Double(_), Value::Integer(_) | Value::Double(_)) => {
+            Ok(as_double(a).total_cmp(&as_double(b)))
+        }
+        (Value::String(a), Value::String(b)) => Ok(a.cmp(b)),
+        _ => Err(format!("Cannot compare {:?} and {:?}", a, b)),
+    }
+}
+
+/// Whether two values have the same structure, for `==` and `equals`. Unlike `in`
+/// and `match`, an integer is never equal to a double, and functions are
+/// only equal to themselves. A range or the lines of a file are equal to
+/// the list they stand for.
+fn equals(a: &Value, b: &Value) -> Result<bool, String> {
+    match (a, b) {
+        (Value::Range { .. } | Value::Lines(_), Value::Range { .. } | Value::Lines(_) | Value::List(_))
+            | (Value::List(_), Value::Range { .. } | Value::Lines(_)) => equals(&force_list(a)?, &force_list(b)?),
+        (Value::List(a), Value::List(b)) => {
+            if a.len() != b.len() {
+                return Ok(false);
+            }
+            for (a, b) in a.iter().zip(b.iter()) {
+                if !equals(a, b)? {
+                    return Ok(false);
+                }
+            }
+            Ok(true)
+        }
+        (Value::Map(a), Value::Map(b)) => {
+            if a.entries.len() != b.entries.len() {
+                return Ok(false);
+            }
+            for (key, a) in a.iter() {
+                match b.get(key) {
+                    Some(b) if equals(a, b)? => (),
+                    _ => return Ok(false),
+                }
+            }
+            Ok(true)
+        }
+        (Value::Closure(a), Value::Closure(b)) => Ok(Rc::ptr_eq(a, b)),
+        (Value::Integer(a), Value::Integer(b)) => Ok(a == b),
+        (Value::Double(a), Value::Double(b)) => Ok(a == b),
+        (Value::String(a), Value::String(b)) => Ok(a == b),
+        (Value::Boolean(a), Value::Boolean(b)) => Ok(a == b),
+        (Value::Void, Value::Void) => Ok(true),
+        _ => Ok(false),
+    }
+}
+
+/// Sorts values by the key paired with each one, keeping values with equal
+/// keys in the order they were in.
+fn sort_keyed(mut keyed: Vec<(Value, Value)>) -> Result<Value, String> {
+    let mut error = None;
+    keyed.sort_by(|(_, a), (_, b)| {
+        compare(a, b).unwrap_or_else(|e| {
+            error.get_or_insert(e);
+            std::cmp::Ordering::Equal
+        })
+    });
+    match error {
+        Some(e) => Err(e),
+        None => Ok(Value::List(Rc::new(keyed.into_iter().map(|(value, _)| value).collect()))),
+    }
+}
+
+/// Renders a value like `Display`, except that a list holding other lists is
+/// spread over several lines, with each element indented by `indent` spaces
+/// more than the list itself.
+/// 
+/// Example:
+/// ```clarice
+/// print pretty [[1, 2], [3, 4]]
+/// # -> [
+/// #        [1, 2],
+/// #        [3, 4]
+/// #    ]
+/// ```
+fn pretty(value: &Value, indent: usize, depth: usize) -> String {
+    let elements = match value {
+        Value::List(elements) if elements.iter().any(|element| matches!(element, Value::List(_))) => elements,
+        Value::String(s) if depth > 0 => return format!("{:?}", s),
+        Value::Void if depth > 0 => return "void".to_string(),
+        _ => return value.to_string(),
+    };
+    let padding = " ".repeat(indent * (depth + 1));
+    let lines: Vec<String> = elements.iter()
+        .map(|element| format!("{}{}", padding, pretty(element, indent, depth + 1)))
+        .collect();
+    format!("[\n{}\n{}]", lines.join(",\n"), " ".repeat(indent * depth))
+}
+
+/// The most elements a range can have to be turned into a list.
+const MAX_LIST_LENGTH: i128 = 10_000_000;
+
+/// The longest string, in bytes, that repeating a string with `*` can make.
+const MAX_STRING_LENGTH: usize = 100_000_000;
+
+/// The integers in a range, in order. This stops at the end of the range,
+/// or just before counting past the largest or smallest integer.
+fn range_values(start: i64, end: i64, step: i64) -> impl Iterator<Item = i64> {
+    std::iter::successors(Some(start), move |n| n.checked_add(step))
+        .take_while(move |n| if step > 0 { *n < end } else { *n > end })
+}
+
+/// How many integers are in a range, worked out without counting them.
+fn range_length(start: i64, end: i64, step: i64) -> i128 {
+    let (start, end, step) = (start as i128, end as i128, step as i128);
+    let distance = if step > 0 { end - start } else { start - end };
+    if distance <= 0 {
+        0
+    }
+    else {
+        (distance + step.abs() - 1) / step.abs()
+    }
+}
+
+/// Whether an integer is one of the integers in a range.
+fn range_contains(start: i64, end: i64, step: i64, n: i64) -> bool {
+    let within = if step > 0 { start <= n && n < end } else { end < n && n <= start };
+    within && (n as i128 - start as i128) % step as i128 == 0
+}
+
+/// Turns a range or the lines of a file into a list, for when its elements
+/// are all needed at once. Anything else is given back as it is.
+pub fn force_list(value: &Value) -> Result<Value, String> {
+    match value {
+        Value::Range { start, end, step } => {
+            let length = range_length(*start, *end, *step);
+            if length > MAX_LIST_LENGTH {
+                return Err(format!(
+                    "{} has {} elements, which is too many to make into a list (the most is {})",
+                    value, length, MAX_LIST_LENGTH
+                ));
+            }
+            Ok(Value::List(Rc::new(range_values(*start, *end, *step).map(Value::Integer).collect())))
+        }
+        Value::Lines(path) => match std::fs::read_to_string(path.as_ref()) {
+            Ok(text) => Ok(Value::List(Rc::new(text.lines().map(|line| Value::String(line.into())).collect()))),
+            Err(e) => Err(format!("Couldn't read {}: {}", path, e)),
+        },
+        _ => Ok(value.clone()),
+    }
+}
+
+/// Applies a binary operator to two values.
+/// 
+/// `in` checks whether a list contains an element, or whether a string
+/// contains a substring. `+`, `-`, `*` and `/` do arithmetic, giving an
+/// integer for two integers and a double otherwise. `+` also joins two
+/// lists together, and `*` repeats a string a number of times. `..` joins
+/// two strings, turning a number on either side into a string the way `str`
+/// would, unless strict coercion is on (which `evaluate_expression` checks).
+/// 
+/// `value or default` gives `default` if `value` is void, and `value`
+/// otherwise, even if it's `false`, `0` or empty. `evaluate_expression`
+/// only works out `default` if it's needed, and treats a variable that
+/// isn't defined as void, so `maybe or 0` is `0` until `maybe` is set.
+/// 
+/// Example:
+/// ```clarice
+/// print "ell" in "hello"
+/// # -> true
+/// print [1, 2] + [3, 4]
+/// # -> [1, 2, 3, 4]
+/// print "ab" * 3
+/// # -> ababab
+/// print "n is " .. 1 + 2
+/// # -> n is 3
+/// print get_env "NO_SUCH_VARIABLE" or "unset"
+/// # -> unset
+/// ```
+pub fn apply_binary_operation(left: &Value, operator: &str, right: &Value) -> Result<Value, String> {
+    match (operator, left, right) {
+        ("or", Value::Void, _) => Ok(right.clone()),
+        ("or", _, _) => Ok(left.clone()),
+        (
+            "..",
+            Value::String(_) | Value::Integer(_) | Value::Double(_),
+            Value::String(_) | Value::Integer(_) | Value::Double(_),
+        ) => Ok(Value::String(format!("{}{}", left, right).into())),
+        ("in", Value::Integer(n), Value::Range { start, end, step }) => {
+            Ok(Value::Boolean(range_contains(*start, *end, *step, *n)))
+        }
+        ("in", _, Value::Range { .. }) => Ok(Value::Boolean(false)),
+        ("in", _, Value::Lines(_))
+            | ("+", Value::Range { .. } | Value::Lines(_), _)
+            | ("+", _, Value::Range { .. } | Value::Lines(_)) => {
+            apply_binary_operation(&force_list(left)?, operator, &force_list(right)?)
+        }
+        ("in", _, Value::List(l)) => Ok(Value::Boolean(l.contains(left))),
+        ("in", Value::String(sub), Value::String(s)) => Ok(Value::Boolean(s.contains(&**sub))),
+        ("+", Value::List(a), Value::List(b)) => {
+            Ok(Value::List(Rc::new(a.iter().chain(b.iter()).cloned().collect())))
+        }
+        ("*", Value::String(s), Value::Integer(n)) | ("*", Value::Integer(n), Value::String(s)) => {
+            let count = usize::try_from(*n).map_err(|_| format!("Cannot repeat a string {} times", n))?;
+            match s.len().checked_mul(count) {
+                Some(length) if length <= MAX_STRING_LENGTH => Ok(Value::String(s.repeat(count).into())),
+                _ => Err(format!("Repeating a string {} times would make it too long (the most is {} bytes)", n, MAX_STRING_LENGTH)),
+            }
+        }
+        ("+" | "-" | "*" | "/", Value::Integer(a), Value::Integer(b)) => {
+            let result = match operator {
+                "+" => a.checked_add(*b),
+                "-" => a.checked_sub(*b),
+                "*" => a.checked_mul(*b),
+                _ => a.checked_div(*b),
+            };
+            match result {
+                Some(i) => Ok(Value::Integer(i)),
+                None if operator == "/" => Err(format!("Cannot divide {} by zero", a)),
+                None => Err(format!("`{} {} {}` is too big for an integer", a, operator, b)),
+            }
+        }
+        ("+" | "-" | "*" | "/", Value::Integer(_) | Value::Double(_), Value::Integer(_) | Value::Double(_)) => {
+            let (a, b) = (as_double(left), as_double(right));
+            Ok(Value::Double(match operator {
+                "+" => a + b,
+                "-" => a - b,
+                "*" => a * b,
+                _ => a / b,
+            }))
+        }
+        ("==", _, _) => Ok(Value::Boolean(equals(left, right)?)),
+        ("!=", _, _) => Ok(Value::Boolean(!equals(left, right)?)),
+        ("<" | ">" | "<=" | ">=", _, _) => {
+            // A double is compared as one, so that NaN is neither less than,
+            // greater than nor equal to anything, unlike the order `sort` gives it
+            let ordering = match (left, right) {
+                (Value::Double(_), Value::Integer(_) | Value::Double(_)) | (Value::Integer(_), Value::Double(_)) => {
+                    as_double(left).partial_cmp(&as_double(right))
+                }
+                _ => Some(compare(left, right)?),
+            };
+            Ok(Value::Boolean(ordering.is_some_and(|ordering| match operator {
+                "<" => ordering.is_lt(),
+                ">" => ordering.is_gt(),
+                "<=" => ordering.is_le(),
+                _ => ordering.is_ge(),
+            })))
+        }
+        _ => Err(format!("Cannot apply `{}` to {:?} and {:?}", operator, left, right)),
+    }
+}
+
+/// Checks that a value can be put in a variable with `set` or `with`. Void
+/// can't be, as it's the lack of a value, e.g. from `print` or from
+/// `get_env` with a variable that isn't set. The type checker catches most
+/// of these, but not without type checking, or where the type it knows of
+/// is only what the value would be if it were there.
+fn bindable(value: Value) -> Result<Value, String> {
+    match value {
+        Value::Void => Err("Cannot bind void to a variable - use `or` to give it a default".to_string()),
+        value => Ok(value),
+    }
+}
+
+/// Whether a value counts as true in truthiness mode: everything except `0`,
+/// `0.0`, `""`, `[]`, `false` and a void.
+pub fn is_truthy(value: &Value) -> bool {
+    match value {
+        Value::Integer(i) => *i != 0,
+        Value::Double(d) => *d != 0.0,
+        Value::String(s) => !s.is_empty(),
+        Value::Boolean(b) => *b,
+        Value::List(l) => !l.is_empty(),
+        Value::Map(map) => !map.is_empty(),
+        Value::Range { start, end, step } => range_values(*start, *end, *step).next().is_some(),
+        // Finding out whether a file has any lines would mean reading it
+        Value::Closure(_) | Value::Lines(_) => true,
+        Value::Void => false,
+    }
+}
+
+/// A function written in Rust that a Clarice program can call.
+pub type NativeFunction = Box<dyn Fn(Vec<Value>) -> Result<Value, RuntimeError>>;
+
 pub struct Environment {
     pub variables: HashMap<String, Value>,
+    /// Stops a `loop` after this many iterations, so a runaway loop can't hang
+    /// the interactive mode.
+    pub max_iterations: Option<usize>,
+    /// Lets conditions be any value rather than only booleans. Off by default.
+    pub truthiness: bool,
+    /// Prints booleans as `yes` and `no` instead of `true` and `false`. Off by
+    /// default.
+    pub yes_no: bool,
+    /// Makes `..` only join strings, so a number has to go through `str`
+    /// first. Off by default.
+    pub strict_coercion: bool,
+    /// Shares one copy of each small string bound by a loop, rather than
+    /// allocating a new string every iteration. On by default.
+    pub intern_strings: bool,
+    interned: HashSet<Rc<str>>,
+    /// Where `print` writes to. Standard output by default.
+    pub output: Box<dyn Write>,
+    /// Stops a program once it has printed this many bytes, so a runaway
+    /// loop can't flood the interactive mode.
+    pub max_output_bytes: Option<usize>,
+    output_bytes: usize, // How many bytes the current program has printed
+    /// The files being imported, innermost last, starting with the file being
+    /// run if there is one. Imports are found relative to the last one.
+    pub imports: Vec<PathBuf>,
+    /// Functions given by a program embedding Clarice, by name.
+    natives: HashMap<String, NativeFunction>,
 }
 
 impl Environment {
     pub fn new() -> Self {
         Environment {
-            variables: HashMap::new(),
+            variables: constants(),
+            max_iterations: None,
+            truthiness: false,
+            yes_no: false,
+            strict_coercion: false,
+            intern_strings: true,
+            interned: HashSet::new(),
+            output: Box::new(std::io::stdout()),
+            max_output_bytes: None,
+            output_bytes: 0,
+            imports: Vec::new(),
+            natives: HashMap::new(),
+        }
+    }
+
+    /// Lets Clarice programs call a function written in Rust, for programs
+    /// that embed Clarice. It's called like any other function, but only if
+    /// there's no function of the program's own or built-in function with
+    /// the same name. The type checker doesn't know about it unless it's
+    /// given its type with `TypeChecker::declare`.
+    pub fn register_native(
+        &mut self,
+        name: impl Into<String>,
+        function: impl Fn(Vec<Value>) -> Result<Value, RuntimeError> + 'static,
+    ) {
+        self.natives.insert(name.into(), Box::new(function));
+    }
+
+    /// Renders a value the way `print` shows it.
+    pub fn show(&self, value: &Value) -> String {
+        if self.yes_no {
+            YesNo(value).to_string()
+        }
+        else {
+            value.to_string()
+        }
+    }
+
+    /// Records the current variables, so that anything a program does to them
+    /// can be undone. Lists and strings are shared rather than copied, so this
+    /// is cheap even when they're big.
+    pub fn snapshot(&self) -> EnvSnapshot {
+        EnvSnapshot {
+            variables: self.variables.clone(),
+        }
+    }
+
+    /// Puts the variables back to how they were when `snapshot` was taken,
+    /// dropping any that have been set since.
+    pub fn restore(&mut self, snapshot: EnvSnapshot) {
+        self.variables = snapshot.variables;
+    }
+
+    /// Gives back the pooled copy of a small string, adding it to the pool
+    /// if it isn't there yet.
+    fn intern(&mut self, s: &str) -> Rc<str> {
+        const MAX_INTERNED_LENGTH: usize = 16;
+        if !self.intern_strings || s.len() > MAX_INTERNED_LENGTH {
+            return s.into();
+        }
+        if let Some(interned) = self.interned.get(s) {
+            return interned.clone();
         }
+        let interned: Rc<str> = s.into();
+        self.interned.insert(interned.clone());
+        interned
     }
 
     pub fn set(&mut self, name: String, value: Value) {
@@ -35,32 +813,126 @@ impl Environment {
         self.variables.get(name)
     }
 
-    pub fn interpret(&mut self, program: ASTNode) {
-        match program {
-            ASTNode::Program(statements) => {
-                for statement in statements {
-                    self.execute_statement(&statement);
-                }
+    /// Runs a program, stopping at the first runtime error. If the program
+    /// ends with an expression, its value is given back. An `exit` gives back
+    /// an error with its `exit_code` set, rather than exiting from here.
+    pub fn interpret(&mut self, program: ASTNode) -> Result<Option<Value>, RuntimeError> {
+        let ASTNode::Program(statements) = program;
+        self.output_bytes = 0;
+        match self.execute_block(&statements).map_err(outside_loop)? {
+            Value::Void => Ok(None),
+            value => Ok(Some(value)),
+        }
+    }
+
+    /// Executes a statement, giving back its value: the value of an
+    /// expression statement, the value of the block a `where` or `match`
+    /// statement ran, or a void for anything else.
+    fn execute_statement(&mut self, statement: &Statement) -> Result<Value, Interrupt> {
+        self.execute_work(vec![Work::Statement(statement)])
+    }
+
+    /// Executes a block, giving back the value of its last statement.
+    fn execute_block(&mut self, statements: &[Statement]) -> Result<Value, Interrupt> {
+        let mut work = Vec::new();
+        push_block(&mut work, statements);
+        self.execute_work(work)
+    }
+
+    /// Does everything on a work stack, giving back the value of the last
+    /// statement run. If it stops early, any `with` variables still bound
+    /// are dropped on the way out.
+    fn execute_work(&mut self, mut work: Vec<Work>) -> Result<Value, Interrupt> {
+        let mut value = Value::Void;
+        let result = loop {
+            let Some(next) = work.pop() else {
+                break Ok(value);
+            };
+            if let Err(interrupt) = self.execute_step(next, &mut work, &mut value) {
+                break Err(interrupt);
+            }
+        };
+        while let Some(next) = work.pop() {
+            if let Work::Unbind { name, previous } = next {
+                self.unbind(name, previous);
+            }
+        }
+        result
+    }
+
+    /// Does one thing from a work stack. A statement that runs a block puts
+    /// it on the stack, and the last statement of that block gives `value`.
+    fn execute_step<'a>(&mut self, next: Work<'a>, work: &mut Vec<Work<'a>>, value: &mut Value) -> Result<(), Interrupt> {
+        let statement = match next {
+            Work::Statement(statement) => statement,
+            Work::Discard => {
+                *value = Value::Void;
+                return Ok(());
+            }
+            Work::Unbind { name, previous } => {
+                self.unbind(name, previous);
+                return Ok(());
+            }
+        };
+        *value = Value::Void;
+        match &statement.kind {
+            StatementKind::With(with_statement) => self.execute_with(with_statement, work),
+            StatementKind::Set(set_statement) => self.execute_set(set_statement),
+            StatementKind::Destructure(destructure_statement) => self.execute_destructure(destructure_statement),
+            StatementKind::As(as_statement) => self.execute_as(as_statement),
+            StatementKind::To(to_statement) => self.execute_to(to_statement),
+            StatementKind::Then(then_statement) => self.execute_then(then_statement, work),
+            StatementKind::Do(do_statement) => self.execute_do(do_statement, work),
+            StatementKind::Print(print_statement) => self.execute_print(print_statement),
+            StatementKind::Where(where_statement) => self.execute_where(where_statement, work),
+            StatementKind::Loop(loop_statement) => self.execute_loop(loop_statement),
+            StatementKind::While(while_statement) => self.execute_while(while_statement),
+            StatementKind::Iter(iter_statement) => self.execute_iter(iter_statement),
+            StatementKind::Exit(exit_statement) => self.execute_exit(exit_statement),
+            StatementKind::Match(match_statement) => self.execute_match(match_statement, work),
+            StatementKind::Function(function_statement) => self.execute_function(function_statement),
+            StatementKind::Break(label) => self.execute_break(label),
+            StatementKind::Next => self.execute_next(),
+            StatementKind::Pass | StatementKind::Comment(..) => Ok(()),
+            StatementKind::Error => unreachable!("a program with parse errors is never run"),
+            StatementKind::Import(import_statement) => self.execute_import(import_statement),
+            StatementKind::Expression(expression) => {
+                *value = self.evaluate_expression(expression)?;
+                Ok(())
             }
         }
     }
 
-    fn execute_statement(&mut self, statement: &Statement) {
-        match statement {
-            Statement::With(with_statement) => self.execute_with(with_statement),
-            Statement::Set(set_statement) => self.execute_set(set_statement),
-            Statement::As(as_statement) => self.execute_as(as_statement),
-            Statement::To(to_statement) => self.execute_to(to_statement),
-            Statement::Then(then_statement) => self.execute_then(then_statement),
-            Statement::Do(do_statement) => self.execute_do(do_statement),
-            Statement::Print(print_statement) => self.execute_print(print_statement),
-            Statement::Where(where_statement) => self.execute_where(where_statement),
-            Statement::Loop(loop_statement) => self.execute_loop(loop_statement),
-            Statement::Iter(iter_statement) => self.execute_iter(iter_statement),
-            Statement::Expression(expression) => { self.evaluate_expression(expression); },
+    /// Binds the variable of a `with` statement, giving back what it hid.
+    fn bind(&mut self, with_statement: &WithStatement) -> Result<Option<Value>, Interrupt> {
+        let value = bindable(self.evaluate_expression(&with_statement.expression)?)?;
+        Ok(self.variables.insert(with_statement.identifier.clone(), value))
+    }
+
+    /// Puts a variable back to what it was before a `with` bound it, or drops
+    /// it if it wasn't there.
+    fn unbind(&mut self, name: &String, previous: Option<Value>) {
+        match previous {
+            Some(value) => self.set(name.clone(), value),
+            None => {
+                self.variables.remove(name);
+            }
         }
     }
 
+    /// Binds the variable of a `with` statement, runs `run` while it's bound,
+    /// and then puts the variable back to what it was before (or drops it).
+    fn with_binding<T>(
+        &mut self,
+        with_statement: &WithStatement,
+        run: impl FnOnce(&mut Self) -> Result<T, Interrupt>,
+    ) -> Result<T, Interrupt> {
+        let previous = self.bind(with_statement)?;
+        let result = run(self);
+        self.unbind(&with_statement.identifier, previous);
+        result
+    }
+
     /// The `with` statement creates a temporary variable which is dropped
     /// immediately at the end of the succeeding statement (i.e., at the start
     /// of the next `then` or newline).
@@ -72,33 +944,83 @@ impl Environment {
     /// # -> 2
     /// # After that `print` statement is done, `x` is dropped, and it can't be used anymore
     /// ```
-    fn execute_with(&mut self, with_statement: &WithStatement) {
-        let value = self.evaluate_expression(&with_statement.expression);
-        self.variables.insert(with_statement.identifier.clone(), value);
+    fn execute_with<'a>(&mut self, with_statement: &'a WithStatement, work: &mut Vec<Work<'a>>) -> Result<(), Interrupt> {
+        let previous = self.bind(with_statement)?;
+        work.push(Work::Unbind { name: &with_statement.identifier, previous });
+        if let Some(body) = &with_statement.body {
+            work.push(Work::Statement(body));
+        }
+        Ok(())
     }
 
     /// The `set` statement assigns a value to a variable permanently, unlike
     /// `with` which drops the variable immediately.
     /// `set` requires an expression followed by the `to` keyword and a value.
     /// 
+    /// With an index after the variable, `set` changes one element of a list
+    /// or one key of a map instead, adding the key if it isn't there yet.
+    /// 
     /// Example:
     /// ```clarice
     /// set x to "Hello!" then print x
     /// # -> Hello!
+    /// set ages to {"Ada": 36}
+    /// set ages["Alan"] to 41
+    /// print ages
+    /// # -> {"Ada": 36, "Alan": 41}
+    /// ```
+    fn execute_set(&mut self, set_statement: &SetStatement) -> Result<(), Interrupt> {
+        let value = bindable(self.evaluate_expression(&set_statement.expression)?)?;
+        if set_statement.indexes.is_empty() {
+            self.set(set_statement.variable.clone(), value);
+            return Ok(());
+        }
+        let indexes = set_statement.indexes.iter()
+            .map(|index| self.evaluate_expression(index))
+            .collect::<Result<Vec<_>, _>>()?;
+        match self.variables.get_mut(&set_statement.variable) {
+            Some(target) => Ok(set_index(target, &indexes, value)?),
+            None => Err(RuntimeError::new(format!(
+                "No variable `{}` - use `with` or `set` to define it",
+                set_statement.variable
+            )).into()),
+        }
+    }
+
+    /// `set` can also take a list of variables in square brackets, setting
+    /// each one to the matching element of a list of the same length.
+    /// 
+    /// Example:
+    /// ```clarice
+    /// set [a, b] to [1, 2] then print a + b
+    /// # -> 3
     /// ```
-    fn execute_set(&mut self, set_statement: &SetStatement) {
-        let value = self.evaluate_expression(&set_statement.expression);
-        self.variables.insert(set_statement.variable.clone(), value);
+    fn execute_destructure(&mut self, destructure_statement: &DestructureStatement) -> Result<(), Interrupt> {
+        let value = self.evaluate_expression(&destructure_statement.expression)?;
+        let elements = match force_list(&value)? {
+            Value::List(elements) => elements,
+            value => return Err(RuntimeError::new(format!("`set [...]` expects a list, got {:?}", value)).into()),
+        };
+        if elements.len() != destructure_statement.variables.len() {
+            return Err(RuntimeError::new(format!(
+                "`set [...]` has {} variable(s), but the list has {} element(s)",
+                destructure_statement.variables.len(), elements.len()
+            )).into());
+        }
+        for (variable, element) in destructure_statement.variables.iter().zip(elements.iter().cloned()) {
+            self.set(variable.clone(), element);
+        }
+        Ok(())
     }
 
     /// `as` is used only with `with` - it cannot be used on its own.
-    fn execute_as(&mut self, _as_statement: &AsStatement) {
-        eprintln!("`as` cannot be used on its own - use `with expr as val`")
+    fn execute_as(&mut self, _as_statement: &AsStatement) -> Result<(), Interrupt> {
+        Err(RuntimeError::new("`as` cannot be used on its own - use `with expr as val`".to_string()).into())
     }
 
     /// `to` is used only with `set` - it cannot be used on its own.
-    fn execute_to(&mut self, _to_statement: &ToStatement) {
-        eprintln!("`to` cannot be used on its own - use `set var to expr`")
+    fn execute_to(&mut self, _to_statement: &ToStatement) -> Result<(), Interrupt> {
+        Err(RuntimeError::new("`to` cannot be used on its own - use `set var to expr`".to_string()).into())
     }
 
     /// The `then` statement is used for separating statements without using
@@ -109,8 +1031,10 @@ impl Environment {
     /// set x to "Hello!" then print x
     /// # -> Hello!
     /// ```
-    fn execute_then(&mut self, then_statement: &ThenStatement) {
-        self.execute_statement(&then_statement.statement);
+    fn execute_then<'a>(&mut self, then_statement: &'a ThenStatement, work: &mut Vec<Work<'a>>) -> Result<(), Interrupt> {
+        work.push(Work::Discard);
+        work.push(Work::Statement(&then_statement.statement));
+        Ok(())
     }
 
     /// The `do` statement is used to create a block, like `:` in Python or the
@@ -123,9 +1047,11 @@ impl Environment {
     /// # -> This block is indented!
     /// ```
     /// 
-    /// TODO: Add support for single-line `do` blocks, with `then`
-    fn execute_do(&mut self, do_statement: &DoStatement) {
-        self.execute_statement(&do_statement.statement);
+    /// A block of a single statement can use `then` instead of `do` and `end`.
+    fn execute_do<'a>(&mut self, do_statement: &'a DoStatement, work: &mut Vec<Work<'a>>) -> Result<(), Interrupt> {
+        work.push(Work::Discard);
+        work.push(Work::Statement(&do_statement.statement));
+        Ok(())
     }
 
     /// The `print` statement prints the value of an expression.
@@ -134,17 +1060,31 @@ impl Environment {
     /// ```clarice
     /// print "Hello, World!"
     /// ```
-    fn execute_print(&mut self, _print_statement: &PrintStatement) {
-        let value = self.evaluate_expression(&_print_statement.expression);
-        match value {
-            Value::String(s) => println!("{}", s),
-            Value::Integer(i) => println!("{}", i),
-            Value::Double(d) => println!("{}", d),
-            Value::Boolean(b) => println!("{}", b),
-            Value::List(l) => println!("{:?}", l),
-            Value::Closure(str, stat) => println!("{:?}, {:?}", str, stat),
-            Value::Void => println!(""),
+    fn execute_print(&mut self, print_statement: &PrintStatement) -> Result<(), Interrupt> {
+        let value = self.evaluate_expression(&print_statement.expression)?;
+        self.write_output(&format!("{}\n", self.show(&value)))
+    }
+
+    /// Writes to the output, or as much as fits under `max_output_bytes`
+    /// followed by "(output truncated)", which stops the program.
+    fn write_output(&mut self, text: &str) -> Result<(), Interrupt> {
+        let write_error = |e: std::io::Error| RuntimeError::new(format!("Couldn't print: {}", e));
+        let remaining = match self.max_output_bytes {
+            Some(max) => max.saturating_sub(self.output_bytes),
+            None => usize::MAX,
+        };
+        if text.len() <= remaining {
+            self.output_bytes += text.len();
+            self.output.write_all(text.as_bytes()).map_err(write_error)?;
+            return Ok(());
+        }
+        let mut end = remaining;
+        while !text.is_char_boundary(end) {
+            end -= 1;
         }
+        self.output.write_all(&text.as_bytes()[..end]).map_err(write_error)?;
+        self.output.write_all(b"\n(output truncated)\n").map_err(write_error)?;
+        Err(RuntimeError::new(format!("Stopped after printing {} bytes", self.output_bytes + end)).into())
     }
 
     /// The `where` statement is used to create a condition, like `if` in
@@ -156,80 +1096,1501 @@ impl Environment {
     ///     print "x is greater than 10"
     /// otherwise do
     ///     print "x is less than or equal to 10"
+    /// end
     /// ```
-    fn execute_where(&mut self, where_statement: &WhereStatement) {
-        let value = self.evaluate_expression(&where_statement.condition);
-        if let Value::Boolean(true) = value {
-            self.interpret(where_statement.true_branch.clone());
+    /// 
+    /// A block runs from `do` to `end`, or `then` can be used for a block of a
+    /// single statement: `where x > 10 then print "big"`.
+    /// 
+    /// There can be more than one condition, separated by commas, and the
+    /// block only runs if all of them hold: `where x in xs, y in ys then
+    /// print "both"`.
+    fn execute_where<'a>(&mut self, where_statement: &'a WhereStatement, work: &mut Vec<Work<'a>>) -> Result<(), Interrupt> {
+        if self.conditions_hold(&where_statement.conditions)? {
+            push_block(work, &where_statement.true_branch);
         }
         else if let Some(false_branch) = &where_statement.false_branch {
-            self.interpret(false_branch.clone());
+            push_block(work, false_branch);
+        }
+        Ok(())
+    }
+
+    /// Checks the conditions of a `where` in order, stopping at the first
+    /// that doesn't hold, so the ones after it aren't worked out.
+    fn conditions_hold(&mut self, conditions: &[Expression]) -> Result<bool, Interrupt> {
+        for condition in conditions {
+            let value = self.evaluate_expression(condition)?;
+            if !self.condition_holds(&value) {
+                return Ok(false);
+            }
         }
+        Ok(true)
     }
 
-    /// The `loop` statement creates an infinite loop.
+    /// The `loop` statement creates an infinite loop, unless the environment
+    /// has an iteration cap.
     /// 
     /// Example:
     /// ```clarice
     /// loop do
     ///     print "Hello, World!"
+    /// end
     /// # -> Hello, World!
     /// ```
-    fn execute_loop(&mut self, loop_statement: &LoopStatement) {
+    fn execute_loop(&mut self, loop_statement: &LoopStatement) -> Result<(), Interrupt> {
+        let mut iterations = 0;
         loop {
-            self.evaluate_expression(&loop_statement.expression.clone());
+            if self.max_iterations.is_some_and(|max| iterations >= max) {
+                return Err(RuntimeError::new(format!("Stopped `loop` after {} iterations", iterations)).into());
+            }
+            if !self.execute_loop_body(loop_statement.label.as_ref(), &loop_statement.body)? {
+                return Ok(());
+            }
+            iterations += 1;
         }
     }
 
-    /// The `iter` statement is like `for` loops in Python and Rust.
+    /// Runs one iteration of a loop's block, giving back whether the loop
+    /// should carry on, which it shouldn't after a `break`. A `break` with
+    /// the label of a loop further out carries on out to it.
+    fn execute_loop_body(&mut self, label: Option<&String>, body: &[Statement]) -> Result<bool, Interrupt> {
+        match self.execute_block(body) {
+            Ok(_) | Err(Interrupt::Next) => Ok(true),
+            Err(Interrupt::Break(None)) => Ok(false),
+            Err(Interrupt::Break(Some(target))) if label == Some(&target) => Ok(false),
+            Err(interrupt) => Err(interrupt),
+        }
+    }
+
+    /// The `import` statement runs another file as if it were written in its
+    /// place, so that the variables and functions it sets can be used
+    /// afterwards. The path is relative to the file doing the importing.
+    /// 
+    /// With `as`, the file runs separately and everything it sets is named
+    /// after the import instead, so that names from different files can't
+    /// clash.
     /// 
     /// Example:
     /// ```clarice
-    /// iter x in "Hello, World!" do
-    ///     print x .. " "
-    /// # -> H e l l o   ,   W o r l d !
+    /// import "utils.clar"
+    /// print double_it 21
+    /// # -> 42
+    /// import "math.clar" as math
+    /// print math.add 1 2
+    /// # -> 3
     /// ```
-    fn execute_iter(&mut self, iter_statement: &IterStatement) {
-        let iterable = self.evaluate_expression(&iter_statement.iterable);
-        match iterable {
-            Value::String(s) => {
-                for c in s.chars() {
-                    self.variables.insert(iter_statement.variable.clone(), Value::String(c.to_string()));
-                    self.evaluate_expression(&iter_statement.expression.clone());
-                }
-            }
-            Value::Integer(i) => {
-                for _ in 0..i {
-                    self.evaluate_expression(&iter_statement.expression.clone());
-                }
-            }
-            Value::List(l) => {
-                for v in l {
-                    let value = self.evaluate_expression(&v);
-                    self.variables.insert(iter_statement.variable.clone(), value);
-                    self.evaluate_expression(&iter_statement.expression.clone());
+    fn execute_import(&mut self, import_statement: &ImportStatement) -> Result<(), Interrupt> {
+        let path = resolve_import(&import_statement.path, self.imports.last().map(PathBuf::as_path))?;
+        check_import_cycle(&self.imports, &path)?;
+        let ASTNode::Program(statements) = parse_import(&path)?;
+        self.imports.push(path);
+        // With a name for the import, the file runs with variables of its own,
+        // which are then given that name as a prefix
+        let importer = import_statement.alias.as_ref().map(|_| std::mem::replace(&mut self.variables, constants()));
+        // A `break` in the imported file can't leave a loop around the `import`
+        let result = self.execute_block(&statements).map_err(outside_loop);
+        if let (Some(alias), Some(importer)) = (&import_statement.alias, importer) {
+            let imported = std::mem::replace(&mut self.variables, importer);
+            let constants = constants();
+            for (name, value) in imported {
+                if constants.get(&name) != Some(&value) {
+                    self.set(format!("{}.{}", alias, name), value);
                 }
             }
-            _ => eprintln!("Cannot iterate over {:?}", iterable),
         }
+        self.imports.pop();
+        result?;
+        Ok(())
     }
 
-    fn evaluate_expression(&mut self, expression: &Expression) -> Value {
-        match expression {
-            Expression::Identifier(id) => {
-                if let Some(value) = self.variables.get(id) {
-                    return value.clone();
-                } else {
-                    eprintln!("No variable `{}` - use `with` or `set` to define it", id);
-                    return Value::Void;
-                }
-            }
-            Expression::IntegerLiteral(i) => Value::Integer(*i),
-            Expression::DoubleLiteral(d) => Value::Double(*d),
-            Expression::BooleanLiteral(b) => Value::Boolean(*b),
-            Expression::StringLiteral(s) => Value::String(s.clone()),
-            Expression::ListLiteral(l) => Value::List(l.clone()),
-            Expression::FunctionCall(n, f) => Value::Closure(n.clone(), f.clone()),
-        }
+    /// The `break` statement leaves the innermost `loop`, `while` or `iter`,
+    /// or with a label, the `loop` with that label and any loops inside it.
+    /// 
+    /// Example:
+    /// ```clarice
+    /// iter n in range 0 9223372036854775807 do
+    ///     where n in [3] then break
+    ///     print n
+    /// end
+    /// # -> 0
+    /// # -> 1
+    /// # -> 2
+    /// ```
+    fn execute_break(&mut self, label: &Option<String>) -> Result<(), Interrupt> {
+        Err(Interrupt::Break(label.clone()))
+    }
+
+    /// The `next` statement skips the rest of the innermost loop's block,
+    /// carrying on with its next iteration. `while` checks its condition
+    /// again first, and `iter` moves on to the next element.
+    /// 
+    /// Example:
+    /// ```clarice
+    /// iter n in [1, 2, 3] do
+    ///     where n in [2] then next
+    ///     print n
+    /// end
+    /// # -> 1
+    /// # -> 3
+    /// ```
+    fn execute_next(&mut self) -> Result<(), Interrupt> {
+        Err(Interrupt::Next)
     }
-}
\ No newline at end of file
+
+    /// The `while` statement repeats its block for as long as its condition
+    /// holds, checking the condition before each iteration.
+    /// 
+    /// Example:
+    /// ```clarice
+    /// set x to 3
+    /// while x do
+    ///     print x
+    ///     set x to x - 1
+    /// end
+    /// # -> 3
+    /// # -> 2
+    /// # -> 1
+    /// ```
+    /// (This example relies on truthiness mode, where `0` counts as false.)
+    fn execute_while(&mut self, while_statement: &WhileStatement) -> Result<(), Interrupt> {
+        let mut iterations = 0;
+        loop {
+            let value = self.evaluate_expression(&while_statement.condition)?;
+            if !self.condition_holds(&value) {
+                return Ok(());
+            }
+            if self.max_iterations.is_some_and(|max| iterations >= max) {
+                return Err(RuntimeError::new(format!("Stopped `while` after {} iterations", iterations)).into());
+            }
+            if !self.execute_loop_body(None, &while_statement.body)? {
+                return Ok(());
+            }
+            iterations += 1;
+        }
+    }
+
+    /// The `match` statement works out a value once, then runs the block of
+    /// the first `case` equal to it, or the `otherwise` block if none are.
+    /// 
+    /// Example:
+    /// ```clarice
+    /// match 2 do
+    ///     case 1 then print "one"
+    ///     case 2 then print "two"
+    ///     otherwise then print "something else"
+    /// end
+    /// # -> two
+    /// ```
+    fn execute_match<'a>(&mut self, match_statement: &'a MatchStatement, work: &mut Vec<Work<'a>>) -> Result<(), Interrupt> {
+        let value = self.evaluate_expression(&match_statement.scrutinee)?;
+        for (pattern, body) in &match_statement.cases {
+            if self.evaluate_expression(pattern)? == value {
+                push_block(work, body);
+                return Ok(());
+            }
+        }
+        if let Some(default) = &match_statement.default {
+            push_block(work, default);
+        }
+        Ok(())
+    }
+
+    /// The `fn` statement defines a function. The function remembers the
+    /// variables around it as they were when it was defined, so a function
+    /// made inside another function keeps working after that one returns.
+    /// 
+    /// Example:
+    /// ```clarice
+    /// fn adder(x) do
+    ///     fn add(y) do x + y end
+    ///     add
+    /// end
+    /// set add_two to adder 2
+    /// print add_two 3
+    /// # -> 5
+    /// ```
+    fn execute_function(&mut self, function_statement: &FunctionStatement) -> Result<(), Interrupt> {
+        let closure = self.make_closure(function_statement);
+        self.set(function_statement.name.clone(), closure);
+        Ok(())
+    }
+
+    /// Makes a function value, capturing the variables that can be seen here.
+    fn make_closure(&self, function_statement: &FunctionStatement) -> Value {
+        Value::Closure(Rc::new(Closure {
+            name: function_statement.name.clone(),
+            parameters: function_statement.parameters.clone(),
+            body: function_statement.body.clone(),
+            captured: self.variables.clone(),
+        }))
+    }
+
+    /// Calls a function defined with `fn`. The call sees the variables the
+    /// function captured when it was defined, itself, and its arguments -
+    /// not the caller's variables - and any variables it sets are dropped
+    /// when it returns.
+    /// 
+    /// When the last thing a function does is call itself, the call reuses
+    /// the current call instead of nesting another one, so a function like
+    /// this can count down from any number without running out of stack:
+    /// ```clarice
+    /// fn countdown(n) do
+    ///     match n do
+    ///         case 0 then "done"
+    ///         otherwise then countdown (n - 1)
+    ///     end
+    /// end
+    /// ```
+    fn call_closure(&mut self, closure: Rc<Closure>, mut arguments: Vec<Value>) -> Result<Value, Interrupt> {
+        loop {
+            if arguments.len() != closure.parameters.len() {
+                return Err(RuntimeError::new(format!(
+                    "`{}` takes {} argument(s), but was given {}",
+                    closure.name, closure.parameters.len(), arguments.len()
+                )).into());
+            }
+            let mut frame = closure.captured.clone();
+            // The function can always call itself, even though it wasn't defined yet when it was captured
+            frame.insert(closure.name.clone(), Value::Closure(closure.clone()));
+            frame.extend(closure.parameters.iter().cloned().zip(arguments));
+            let caller = std::mem::replace(&mut self.variables, frame);
+            let result = self.execute_tail_block(&closure.body, &closure);
+            self.variables = caller;
+            match result.map_err(outside_loop)? {
+                TailResult::Value(value) => return Ok(value),
+                TailResult::Call(next_arguments) => arguments = next_arguments,
+            }
+        }
+    }
+
+    /// Executes the body of `closure` like `execute_block`, except that if it
+    /// ends by calling `closure` again, the arguments for that call are given
+    /// back instead of the call being made.
+    fn execute_tail_block(&mut self, statements: &[Statement], closure: &Rc<Closure>) -> Result<TailResult, Interrupt> {
+        let Some((last, rest)) = statements.split_last() else {
+            return Ok(TailResult::Value(Value::Void));
+        };
+        for statement in rest {
+            self.execute_statement(statement)?;
+        }
+        self.execute_tail_statement(last, closure)
+    }
+
+    /// Executes the last statement of a function body, looking through
+    /// `where`, `match` and `with` for a call of the function itself.
+    fn execute_tail_statement(&mut self, statement: &Statement, closure: &Rc<Closure>) -> Result<TailResult, Interrupt> {
+        match &statement.kind {
+            StatementKind::Expression(expression) => match &**expression {
+                Expression::FunctionCall(name, arguments) if self.calls_itself(name, closure) => {
+                    let arguments = arguments.iter()
+                        .map(|argument| self.evaluate_expression(argument))
+                        .collect::<Result<_, _>>()?;
+                    Ok(TailResult::Call(arguments))
+                }
+                _ => Ok(TailResult::Value(self.evaluate_expression(expression)?)),
+            },
+            StatementKind::Where(where_statement) => {
+                if self.conditions_hold(&where_statement.conditions)? {
+                    self.execute_tail_block(&where_statement.true_branch, closure)
+                }
+                else if let Some(false_branch) = &where_statement.false_branch {
+                    self.execute_tail_block(false_branch, closure)
+                }
+                else {
+                    Ok(TailResult::Value(Value::Void))
+                }
+            }
+            StatementKind::Match(match_statement) => {
+                let value = self.evaluate_expression(&match_statement.scrutinee)?;
+                for (pattern, body) in &match_statement.cases {
+                    if self.evaluate_expression(pattern)? == value {
+                        return self.execute_tail_block(body, closure);
+                    }
+                }
+                match &match_statement.default {
+                    Some(default) => self.execute_tail_block(default, closure),
+                    None => Ok(TailResult::Value(Value::Void)),
+                }
+            }
+            StatementKind::With(with_statement) => {
+                self.with_binding(with_statement, |environment| match &with_statement.body {
+                    Some(body) => environment.execute_tail_statement(body, closure),
+                    None => Ok(TailResult::Value(Value::Void)),
+                })
+            }
+            _ => Ok(TailResult::Value(self.execute_statement(statement)?)),
+        }
+    }
+
+    /// Whether calling `name` would call `closure` itself.
+    fn calls_itself(&self, name: &str, closure: &Rc<Closure>) -> bool {
+        matches!(self.get(name), Some(Value::Closure(other)) if Rc::ptr_eq(other, closure))
+    }
+
+    /// Whether a condition passes. Normally only `true` does, but in
+    /// truthiness mode any value other than `0`, `""`, `[]`, `false` or a
+    /// void counts.
+    fn condition_holds(&self, value: &Value) -> bool {
+        if self.truthiness {
+            is_truthy(value)
+        }
+        else {
+            matches!(value, Value::Boolean(true))
+        }
+    }
+
+    /// The `iter` statement is like `for` loops in Python and Rust. It can
+    /// iterate over the characters of a string, the elements of a list, the
+    /// numbers from 0 up to (but not including) an integer, the numbers in
+    /// a range, which are worked out one at a time, the lines of a file
+    /// from `lines_of`, which are read one at a time, or the keys of a map.
+    /// Maps are iterated in the order their keys were first added, and
+    /// `iter k, v in m` gives each value along with its key.
+    /// 
+    /// Example:
+    /// ```clarice
+    /// iter x in "Hello, World!" do
+    ///     print x .. " "
+    /// end
+    /// # -> H e l l o   ,   W o r l d !
+    /// 
+    /// iter name, age in {"Ann": 31, "Bo": 27} do
+    ///     print name
+    ///     print age
+    /// end
+    /// # -> Ann
+    /// # -> 31
+    /// # -> Bo
+    /// # -> 27
+    /// ```
+    fn execute_iter(&mut self, iter_statement: &IterStatement) -> Result<(), Interrupt> {
+        let iterable = self.evaluate_expression(&iter_statement.iterable)?;
+        match iterable {
+            Value::String(s) => {
+                for c in s.chars() {
+                    let c = self.intern(c.encode_utf8(&mut [0; 4]));
+                    self.set(iter_statement.variable.clone(), Value::String(c));
+                    if !self.execute_loop_body(None, &iter_statement.body)? {
+                        break;
+                    }
+                }
+            }
+            Value::Integer(i) => {
+                for n in 0..i {
+                    self.count_iteration(n as usize)?;
+                    self.set(iter_statement.variable.clone(), Value::Integer(n));
+                    if !self.execute_loop_body(None, &iter_statement.body)? {
+                        break;
+                    }
+                }
+            }
+            Value::List(l) => {
+                for value in l.iter().cloned() {
+                    self.set(iter_statement.variable.clone(), value);
+                    if !self.execute_loop_body(None, &iter_statement.body)? {
+                        break;
+                    }
+                }
+            }
+            Value::Range { start, end, step } => {
+                for (iterations, n) in range_values(start, end, step).enumerate() {
+                    self.count_iteration(iterations)?;
+                    self.set(iter_statement.variable.clone(), Value::Integer(n));
+                    if !self.execute_loop_body(None, &iter_statement.body)? {
+                        break;
+                    }
+                }
+            }
+            Value::Lines(path) => {
+                let file = std::fs::File::open(path.as_ref()).map_err(|e| format!("Couldn't read {}: {}", path, e))?;
+                for (iterations, line) in std::io::BufReader::new(file).lines().enumerate() {
+                    self.count_iteration(iterations)?;
+                    let line = line.map_err(|e| format!("Couldn't read {}: {}", path, e))?;
+                    self.set(iter_statement.variable.clone(), Value::String(line.into()));
+                    if !self.execute_loop_body(None, &iter_statement.body)? {
+                        break;
+                    }
+                }
+            }
+            Value::Map(map) => {
+                for (key, value) in map.iter() {
+                    self.set(iter_statement.variable.clone(), Value::from(key));
+                    if let Some(value_variable) = &iter_statement.value_variable {
+                        self.set(value_variable.clone(), value.clone());
+                    }
+                    if !self.execute_loop_body(None, &iter_statement.body)? {
+                        break;
+                    }
+                }
+            }
+            _ => return Err(RuntimeError::new(format!("Cannot iterate over {:?}", iterable)).into()),
+        }
+        Ok(())
+    }
+
+    /// Stops an `iter` once it has gone round `max_iterations` times. An
+    /// integer, a range or the lines of a file can be far too long to
+    /// finish, so they're capped like `loop`.
+    fn count_iteration(&self, iterations: usize) -> Result<(), Interrupt> {
+        if self.max_iterations.is_some_and(|max| iterations >= max) {
+            return Err(RuntimeError::new(format!("Stopped `iter` after {} iterations", iterations)).into());
+        }
+        Ok(())
+    }
+
+    /// The `exit` statement stops the program with an optional integer status
+    /// code, which defaults to 0. `interpret` gives back a `RuntimeError`
+    /// with the code in it, and it's up to whatever ran the program to exit.
+    /// 
+    /// Example:
+    /// ```clarice
+    /// exit 1
+    /// ```
+    fn execute_exit(&mut self, exit_statement: &ExitStatement) -> Result<(), Interrupt> {
+        let code = match &exit_statement.code {
+            Some(code) => match self.evaluate_expression(code)? {
+                Value::Integer(i) => i32::try_from(i)
+                    .map_err(|_| format!("`exit` can't use {} as a status code, as it doesn't fit in 32 bits", i))?,
+                value => {
+                    return Err(RuntimeError::new(format!("`exit` expects an integer status code, got {:?}", value)).into());
+                }
+            },
+            None => 0,
+        };
+        Err(RuntimeError::exit(code).into())
+    }
+
+    fn evaluate_expression(&mut self, expression: &Expression) -> Result<Value, Interrupt> {
+        match expression {
+            Expression::Identifier(id) => {
+                if let Some(value) = self.get(id) {
+                    Ok(value.clone())
+                } else {
+                    Err(RuntimeError::new(format!("No variable `{}` - use `with` or `set` to define it", id)).into())
+                }
+            }
+            Expression::IntegerLiteral(i) => Ok(Value::Integer(*i)),
+            Expression::DoubleLiteral(d) => Ok(Value::Double(*d)),
+            Expression::BooleanLiteral(b) => Ok(Value::Boolean(*b)),
+            Expression::StringLiteral(s) => Ok(Value::String(s.as_str().into())),
+            Expression::MapLiteral(entries) => {
+                let mut map = Map::default();
+                for (key, value) in entries {
+                    let key = self.evaluate_expression(key)?;
+                    let value = self.evaluate_expression(value)?;
+                    map.insert(Key::try_from(&key)?, value);
+                }
+                Ok(Value::Map(Rc::new(map)))
+            }
+            Expression::ListLiteral(l) => {
+                let elements = l.iter()
+                    .map(|element| self.evaluate_expression(element))
+                    .collect::<Result<_, _>>()?;
+                Ok(Value::List(Rc::new(elements)))
+            }
+            Expression::Comprehension(comprehension) => self.evaluate_comprehension(comprehension),
+            Expression::Error => unreachable!("a program with parse errors is never run"),
+            Expression::BinaryOperation(..) => {
+                // A chain like `1 + 2 + 3` groups to the left, so it's worked out
+                // from its innermost operand outwards rather than recursing once
+                // per operator
+                let mut operations = Vec::new();
+                let mut left = expression;
+                while let Expression::BinaryOperation(inner, operator, right) = left {
+                    operations.push((operator, right.as_ref()));
+                    left = inner;
+                }
+                let mut value = match left {
+                    // A variable that isn't defined is void before `or`, like one that's set to void
+                    Expression::Identifier(name) if operations.last().is_some_and(|(operator, _)| *operator == "or")
+                        && self.get(name).is_none() => Value::Void,
+                    left => self.evaluate_expression(left)?,
+                };
+                for (operator, right) in operations.into_iter().rev() {
+                    value = self.evaluate_operation(value, operator, right)?;
+                }
+                Ok(value)
+            }
+            Expression::FunctionCall(name, arguments) => {
+                let arguments: Vec<Value> = arguments.iter()
+                    .map(|argument| self.evaluate_expression(argument))
+                    .collect::<Result<_, _>>()?;
+                match self.get(name) {
+                    Some(Value::Closure(closure)) => self.call_closure(closure.clone(), arguments),
+                    _ => Ok(self.call_function(name, arguments)?),
+                }
+            }
+            Expression::Block(statements) => self.execute_block(statements),
+            Expression::Lambda(function_statement) => Ok(self.make_closure(function_statement)),
+            Expression::Index(expression, index) => {
+                let value = self.evaluate_expression(expression)?;
+                let index = self.evaluate_expression(index)?;
+                Ok(index_into(&value, &index)?)
+            }
+            Expression::Cast(expression, target) => {
+                let value = self.evaluate_expression(expression)?;
+                Ok(self.cast(value, target)?)
+            }
+            Expression::Conditional(condition, true_branch, false_branch) => {
+                // Only the branch that's taken is evaluated
+                let value = self.evaluate_expression(condition)?;
+                if self.condition_holds(&value) {
+                    self.evaluate_expression(true_branch)
+                }
+                else {
+                    self.evaluate_expression(false_branch)
+                }
+            }
+        }
+    }
+
+    /// Works out one operation in a chain, given the value of everything to
+    /// its left. The default after `or` is only worked out if it's needed.
+    fn evaluate_operation(&mut self, left: Value, operator: &str, right: &Expression) -> Result<Value, Interrupt> {
+        if operator == "or" {
+            return match left {
+                Value::Void => self.evaluate_expression(right),
+                value => Ok(value),
+            };
+        }
+        let right = self.evaluate_expression(right)?;
+        if operator == ".." && self.strict_coercion {
+            // Whichever side isn't a string, if either
+            let ((Value::String(_), other) | (other, _)) = (&left, &right);
+            if !matches!(other, Value::String(_)) {
+                return Err(RuntimeError::new(format!(
+                    "`..` only joins strings with strict coercion on - use `str` to turn {:?} into a string",
+                    other
+                )).into());
+            }
+        }
+        Ok(apply_binary_operation(&left, operator, &right)?)
+    }
+
+    /// Builds the list for a list comprehension. It goes through the same
+    /// things as `iter`, and its variable is only bound while the list is
+    /// being built.
+    /// 
+    /// Example:
+    /// ```clarice
+    /// print [c * 2 for c in "abc"]
+    /// # -> ["aa", "bb", "cc"]
+    /// ```
+    fn evaluate_comprehension(&mut self, comprehension: &Comprehension) -> Result<Value, Interrupt> {
+        // An integer stands for the range up to it, which can only be so long as a list
+        let iterable = match self.evaluate_expression(&comprehension.iterable)? {
+            Value::Integer(n) => Value::Range { start: 0, end: n, step: 1 },
+            iterable => iterable,
+        };
+        let items = match iterable {
+            Value::String(s) => s.chars().map(|c| Value::String(c.to_string().into())).collect(),
+            Value::Map(map) => map.iter().map(|(key, _)| Value::from(key)).collect(),
+            iterable @ (Value::List(_) | Value::Range { .. } | Value::Lines(_)) => match force_list(&iterable)? {
+                Value::List(elements) => elements.as_ref().clone(),
+                _ => unreachable!("a range or file is made into a list"),
+            },
+            iterable => return Err(RuntimeError::new(format!("Cannot iterate over {:?}", iterable)).into()),
+        };
+        let previous = self.variables.remove(&comprehension.variable);
+        let result = self.collect_comprehension(comprehension, items);
+        match previous {
+            Some(value) => self.set(comprehension.variable.clone(), value),
+            None => {
+                self.variables.remove(&comprehension.variable);
+            }
+        }
+        result
+    }
+
+    fn collect_comprehension(&mut self, comprehension: &Comprehension, items: Vec<Value>) -> Result<Value, Interrupt> {
+        let mut elements = Vec::new();
+        for item in items {
+            self.set(comprehension.variable.clone(), item);
+            if let Some(condition) = &comprehension.condition {
+                let holds = self.evaluate_expression(condition)?;
+                if !self.condition_holds(&holds) {
+                    continue;
+                }
+            }
+            elements.push(self.evaluate_expression(&comprehension.element)?);
+        }
+        Ok(Value::List(Rc::new(elements)))
+    }
+
+    /// Converts a value for `as`, the same way as the `int`, `double` and `str`
+    /// functions. Casting a double to an integer rounds it towards zero, and
+    /// casting an integer to a double can lose precision past 2^53.
+    /// 
+    /// Example:
+    /// ```clarice
+    /// print 3.9 as integer
+    /// # -> 3
+    /// print "2.5" as double * 2
+    /// # -> 5.0
+    /// ```
+    fn cast(&mut self, value: Value, target: &Type) -> Result<Value, RuntimeError> {
+        match (target, &value) {
+            (Type::Integer, _) => self.call_function("int", vec![value]),
+            (Type::Double, _) => self.call_function("double", vec![value]),
+            (Type::String, _) => self.call_function("str", vec![value]),
+            (Type::Boolean, Value::Boolean(_)) | (Type::Any, _) => Ok(value),
+            (Type::List(element_type), Value::List(_) | Value::Range { .. } | Value::Lines(_)) => {
+                let Value::List(elements) = force_list(&value)? else {
+                    return Err(format!("Cannot convert {:?} to {:?}", value, target).into());
+                };
+                let elements = elements.iter()
+                    .map(|element| self.cast(element.clone(), element_type))
+                    .collect::<Result<_, _>>()?;
+                Ok(Value::List(Rc::new(elements)))
+            }
+            (Type::Map(_, value_type), Value::Map(map)) => {
+                let mut converted = Map::default();
+                for (key, value) in map.iter() {
+                    converted.insert(key.clone(), self.cast(value.clone(), value_type)?);
+                }
+                Ok(Value::Map(Rc::new(converted)))
+            }
+            _ => Err(format!("Cannot convert {:?} to {:?}", value, target).into()),
+        }
+    }
+
+    /// Calls `function` on each element of a list (or range), for `map`,
+    /// `filter`, `count_where` and `sort_by`.
+    fn call_each(&mut self, function: &Rc<Closure>, list: &Value) -> Result<Vec<(Value, Value)>, RuntimeError> {
+        let elements = match force_list(list)? {
+            Value::List(elements) => elements,
+            value => return Err(format!("Expected a list to go through, got {:?}", value).into()),
+        };
+        elements.iter()
+            .map(|element| {
+                let result = self.call_closure(function.clone(), vec![element.clone()]);
+                result.map(|value| (element.clone(), value)).map_err(outside_loop)
+            })
+            .collect()
+    }
+
+    /// Calls one of Clarice's built-in functions.
+    /// 
+    /// The conversion functions `str`, `int` and `double` convert between
+    /// strings and numbers. Doubles are always written with `.` as the decimal
+    /// separator, so `double (str 3.14)` gives back `3.14`. `int` rounds a
+    /// double towards zero, and a double too big to be an integer is an
+    /// error, like `inf` and `nan` are.
+    /// 
+    /// `range start end` gives the integers from `start` up to (but not
+    /// including) `end`, and `range_by start end step` counts by `step`
+    /// instead of 1, which can be negative to count down.
+    /// 
+    /// `sqrt`, `sin`, `cos`, `tan`, `log` and `exp` take an integer or a
+    /// double and give back a double. Angles are in radians, and `log` is
+    /// the natural logarithm.
+    /// 
+    /// `compare a b` gives back -1 if `a` comes before `b`, 0 if they're the
+    /// same, or 1 if `a` comes after `b`, for two numbers or two strings.
+    /// 
+    /// `equals a b` checks whether two values are the same all the way down,
+    /// so lists and maps are compared element by element. An integer never
+    /// equals a double, and a function only equals itself. `a == b` does the
+    /// same, but the checker refuses it when the two can never be equal.
+    /// 
+    /// `index_of list element` gives back the index of the first element equal
+    /// to `element`, and `index_of string substring` the index of the first
+    /// character where `substring` appears. Either gives back void if it
+    /// isn't there, as `-1` would be an index counting from the end.
+    /// 
+    /// `is_empty` checks whether a list, string or map has nothing in it. For
+    /// the lines of a file, it only reads as far as the first byte.
+    /// 
+    /// `len` gives back how many characters are in a string, how many
+    /// elements are in a list or range, or how many keys are in a map.
+    /// `byte_len string` gives back how many bytes a string takes up in UTF-8
+    /// instead, so the two differ for anything outside ASCII.
+    /// ```clarice
+    /// print len "naïve"
+    /// # -> 5
+    /// print byte_len "naïve"
+    /// # -> 6
+    /// ```
+    /// 
+    /// `fixed d places` writes a double rounded to a number of decimal
+    /// places, always showing that many, so `fixed 2.5 2` gives back
+    /// `"2.50"`.
+    /// 
+    /// `almost_equal` compares two doubles while allowing for rounding error,
+    /// as `0.1 + 0.2` isn't exactly `0.3`.
+    /// 
+    /// `map function list` calls a function on each element of a list, giving
+    /// back a list of the results, and `filter function list` keeps the
+    /// elements the function gives back `true` for. `count_where function
+    /// list` counts those elements instead, and `find function list` gives
+    /// back the first of them, or void if there aren't any.
+    /// ```clarice
+    /// print map (fn(x) do x * 2 end) [1, 2, 3]
+    /// # -> [2, 4, 6]
+    /// print count_where (fn(x) do x in [1, 3] end) [1, 2, 3]
+    /// # -> 2
+    /// ```
+    /// 
+    /// `flatten list` joins a list of lists into one list. Only one level is
+    /// taken apart, so lists inside those lists stay as they are.
+    /// ```clarice
+    /// print flatten [[1, 2], [3], [4, 5]]
+    /// # -> [1, 2, 3, 4, 5]
+    /// ```
+    /// 
+    /// `sort list` sorts a list of numbers or strings from smallest to
+    /// largest, and `sort_by function list` sorts a list by what the function
+    /// gives back for each element. Elements that sort the same stay in the
+    /// order they were in.
+    /// ```clarice
+    /// print sort_by (fn(x) do 0 - x end) [2, 3, 1]
+    /// # -> [3, 2, 1]
+    /// ```
+    /// 
+    /// `reduce function initial list` combines a list into one value, by
+    /// calling the function with the value so far (starting at `initial`)
+    /// and each element in turn.
+    /// ```clarice
+    /// fn add(total, x) do total + x end
+    /// print reduce add 0 [1, 2, 3]
+    /// # -> 6
+    /// ```
+    /// 
+    /// `read_file path` gives back everything in a file as a string. The path
+    /// is relative to the directory Clarice was started in. `lines_of path`
+    /// gives the lines of a file without its line endings, reading them only
+    /// as they're needed, so that `iter` can go through a large file without
+    /// holding all of it at once.
+    /// 
+    /// `write_file path text` replaces everything in a file with the text,
+    /// making the file if it isn't there, and `append_file path text` adds
+    /// the text to the end of the file instead.
+    /// 
+    /// `get_env name` gives back the value of an environment variable, or
+    /// void if it isn't set, so it's usually given a default with `or`.
+    fn call_function(&mut self, name: &str, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+        match (name, arguments.as_slice()) {
+            ("str", [value]) => Ok(Value::String(value.to_string().into())),
+            ("int", [Value::Integer(i)]) => Ok(Value::Integer(*i)),
+            // Rounds towards zero. 2^63 is one past the biggest integer, and is exact as a double
+            ("int", [Value::Double(d)]) if (-9223372036854775808.0..9223372036854775808.0).contains(&d.trunc()) => {
+                Ok(Value::Integer(*d as i64))
+            }
+            ("int", [Value::Double(d)]) if d.is_finite() => Err(format!(
+                "Cannot convert {:e} to an integer, as it's too big (the biggest is {})",
+                d, i64::MAX
+            ).into()),
+            ("int", [Value::Double(d)]) => Err(format!("Cannot convert {} to an integer", format_double(*d)).into()),
+            ("int", [Value::String(s)]) => match s.trim().parse() {
+                Ok(i) => Ok(Value::Integer(i)),
+                Err(_) => Err(format!("Cannot convert \"{}\" to an integer", s).into()),
+            },
+            ("double", [Value::Double(d)]) => Ok(Value::Double(*d)),
+            ("double", [Value::Integer(i)]) => Ok(Value::Double(*i as f64)),
+            ("double", [Value::String(s)]) => match s.trim().parse() {
+                Ok(d) => Ok(Value::Double(d)),
+                Err(_) => Err(format!("Cannot convert \"{}\" to a double", s).into()),
+            },
+            ("range", [Value::Integer(start), Value::Integer(end)]) => {
+                Ok(Value::Range { start: *start, end: *end, step: 1 })
+            }
+            ("range_by", [Value::Integer(_), Value::Integer(_), Value::Integer(0)]) => {
+                Err("`range_by` can't count by 0".to_string().into())
+            }
+            ("range_by", [Value::Integer(start), Value::Integer(end), Value::Integer(step)]) => {
+                Ok(Value::Range { start: *start, end: *end, step: *step })
+            }
+            ("almost_equal", [a @ (Value::Integer(_) | Value::Double(_)), b @ (Value::Integer(_) | Value::Double(_))]) => {
+                Ok(Value::Boolean(almost_equal(as_double(a), as_double(b))))
+            }
+            (
+                "sqrt" | "sin" | "cos" | "tan" | "log" | "exp",
+                [x @ (Value::Integer(_) | Value::Double(_))],
+            ) => Ok(Value::Double(math(name, as_double(x))?)),
+            ("fixed", [Value::Double(d), Value::Integer(places)]) => match usize::try_from(*places) {
+                Ok(_) if !d.is_finite() => Ok(Value::String(format_double(*d).into())),
+                Ok(places) => Ok(Value::String(format!("{:.*}", places, d).into())),
+                Err(_) => Err(format!("`fixed` can't show {} decimal places", places).into()),
+            },
+            ("pretty", [value]) => Ok(Value::String(pretty(value, 4, 0).into())),
+            ("pretty_by", [value, Value::Integer(indent)]) => match usize::try_from(*indent) {
+                Ok(indent) => Ok(Value::String(pretty(value, indent, 0).into())),
+                Err(_) => Err(format!("`pretty_by` can't indent by {} spaces", indent).into()),
+            },
+            ("map", [Value::Closure(function), list]) => {
+                let results = self.call_each(function, list)?;
+                Ok(Value::List(Rc::new(results.into_iter().map(|(_, result)| result).collect())))
+            }
+            ("filter", [Value::Closure(function), list]) => {
+                let mut kept = Vec::new();
+                for (element, keep) in self.call_each(function, list)? {
+                    match keep {
+                        Value::Boolean(true) => kept.push(element),
+                        Value::Boolean(false) => (),
+                        other => return Err(format!("`filter` expects its function to give back a boolean, got {:?}", other).into()),
+                    }
+                }
+                Ok(Value::List(Rc::new(kept)))
+            }
+            ("count_where", [Value::Closure(function), list]) => {
+                let mut count = 0;
+                for (_, holds) in self.call_each(function, list)? {
+                    match holds {
+                        Value::Boolean(true) => count += 1,
+                        Value::Boolean(false) => (),
+                        other => return Err(format!("`count_where` expects its function to give back a boolean, got {:?}", other).into()),
+                    }
+                }
+                Ok(Value::Integer(count))
+            }
+            ("find", [Value::Closure(function), list]) => {
+                let elements = match force_list(list)? {
+                    Value::List(elements) => elements,
+                    value => return Err(format!("Expected a list to go through, got {:?}", value).into()),
+                };
+                // Stops at the first match, so the function isn't called on the rest
+                for element in elements.iter() {
+                    let found = self.call_closure(function.clone(), vec![element.clone()]).map_err(outside_loop)?;
+                    match found {
+                        Value::Boolean(true) => return Ok(element.clone()),
+                        Value::Boolean(false) => (),
+                        other => return Err(format!("`find` expects its function to give back a boolean, got {:?}", other).into()),
+                    }
+                }
+                Ok(Value::Void)
+            }
+            ("flatten", [list @ (Value::List(_) | Value::Range { .. } | Value::Lines(_))]) => {
+                let Value::List(sublists) = force_list(list)? else {
+                    unreachable!("a range or file is made into a list")
+                };
+                let mut elements = Vec::new();
+                for sublist in sublists.iter() {
+                    match force_list(sublist)? {
+                        Value::List(sublist) => elements.extend(sublist.iter().cloned()),
+                        other => return Err(format!("`flatten` expects a list of lists, but the list has {:?} in it", other).into()),
+                    }
+                }
+                Ok(Value::List(Rc::new(elements)))
+            }
+            ("json", [value]) => {
+                let mut output = String::new();
+                json::write(value, &mut output)?;
+                Ok(Value::String(output.into()))
+            }
+            ("parse_json", [Value::String(text)]) => Ok(json::parse(text)?),
+            ("to_bytes", [value]) => {
+                let mut output = Vec::new();
+                binary::write(value, &mut output)?;
+                Ok(Value::List(Rc::new(output.into_iter().map(|byte| Value::Integer(byte as i64)).collect())))
+            }
+            ("from_bytes", [list @ (Value::List(_) | Value::Range { .. })]) => {
+                let Value::List(elements) = force_list(list)? else {
+                    unreachable!("a range is made into a list")
+                };
+                let bytes = elements.iter()
+                    .map(|element| match element {
+                        Value::Integer(i) => u8::try_from(*i).map_err(|_| format!("{} isn't a byte - bytes go from 0 to 255", i)),
+                        other => Err(format!("Expected a list of bytes, but it has {:?} in it", other)),
+                    })
+                    .collect::<Result<Vec<u8>, _>>()?;
+                Ok(binary::read(&bytes)?)
+            }
+            ("read_file", [Value::String(path)]) => match std::fs::read_to_string(path.as_ref()) {
+                Ok(text) => Ok(Value::String(text.into())),
+                Err(e) => Err(format!("Couldn't read {}: {}", path, e).into()),
+            },
+            ("compare", [a, b]) => Ok(Value::Integer(compare(a, b)? as i64)),
+            ("equals", [a, b]) => Ok(Value::Boolean(equals(a, b)?)),
+            ("index_of", [Value::String(s), Value::String(sub)]) => match s.find(&**sub) {
+                // Strings are indexed by character, not by byte
+                Some(byte) => Ok(Value::Integer(s[..byte].chars().count() as i64)),
+                None => Ok(Value::Void),
+            },
+            ("index_of", [list @ (Value::List(_) | Value::Range { .. } | Value::Lines(_)), target]) => {
+                let Value::List(elements) = force_list(list)? else {
+                    unreachable!("a range or file is made into a list")
+                };
+                match elements.iter().position(|element| element == target) {
+                    Some(index) => Ok(Value::Integer(index as i64)),
+                    None => Ok(Value::Void),
+                }
+            }
+            ("is_empty", [Value::List(elements)]) => Ok(Value::Boolean(elements.is_empty())),
+            ("is_empty", [Value::String(s)]) => Ok(Value::Boolean(s.is_empty())),
+            ("is_empty", [Value::Map(map)]) => Ok(Value::Boolean(map.is_empty())),
+            ("is_empty", [Value::Range { start, end, step }]) => {
+                Ok(Value::Boolean(range_values(*start, *end, *step).next().is_none()))
+            }
+            // A file has lines unless it's empty, which reading one byte is enough to tell
+            ("is_empty", [Value::Lines(path)]) => {
+                let mut file = std::fs::File::open(path.as_ref()).map_err(|e| format!("Couldn't read {}: {}", path, e))?;
+                let read = file.read(&mut [0]).map_err(|e| format!("Couldn't read {}: {}", path, e))?;
+                Ok(Value::Boolean(read == 0))
+            }
+            ("len", [Value::String(s)]) => Ok(Value::Integer(s.chars().count() as i64)),
+            ("len", [Value::Map(map)]) => Ok(Value::Integer(map.len() as i64)),
+            ("len", [Value::Range { start, end, step }]) => match i64::try_from(range_length(*start, *end, *step)) {
+                Ok(length) => Ok(Value::Integer(length)),
+                Err(_) => Err(format!("range {} {} has too many elements to count", start, end).into()),
+            },
+            ("len", [list @ (Value::List(_) | Value::Lines(_))]) => {
+                let Value::List(elements) = force_list(list)? else {
+                    unreachable!("the lines of a file are made into a list")
+                };
+                Ok(Value::Integer(elements.len() as i64))
+            }
+            ("byte_len", [Value::String(s)]) => Ok(Value::Integer(s.len() as i64)),
+            ("lines_of", [Value::String(path)]) => Ok(Value::Lines(path.clone())),
+            ("get_env", [Value::String(name)]) => match std::env::var_os(name.as_ref()) {
+                Some(value) => Ok(Value::String(value.to_string_lossy().into())),
+                None => Ok(Value::Void),
+            },
+            ("write_file", [Value::String(path), Value::String(text)]) => match std::fs::write(path.as_ref(), text.as_bytes()) {
+                Ok(()) => Ok(Value::Void),
+                Err(e) => Err(format!("Couldn't write {}: {}", path, e).into()),
+            },
+            ("append_file", [Value::String(path), Value::String(text)]) => {
+                let appended = std::fs::OpenOptions::new()
+                    .append(true)
+                    .create(true)
+                    .open(path.as_ref())
+                    .and_then(|mut file| file.write_all(text.as_bytes()));
+                match appended {
+                    Ok(()) => Ok(Value::Void),
+                    Err(e) => Err(format!("Couldn't write {}: {}", path, e).into()),
+                }
+            }
+            ("sort", [list @ (Value::List(_) | Value::Range { .. } | Value::Lines(_))]) => {
+                let Value::List(elements) = force_list(list)? else {
+                    unreachable!("a range is made into a list")
+                };
+                Ok(sort_keyed(elements.iter().map(|element| (element.clone(), element.clone())).collect())?)
+            }
+            ("sort_by", [Value::Closure(function), list]) => Ok(sort_keyed(self.call_each(function, list)?)?),
+            ("reduce", [Value::Closure(function), initial, list]) => {
+                let elements = match force_list(list)? {
+                    Value::List(elements) => elements,
+                    value => return Err(format!("Expected a list to go through, got {:?}", value).into()),
+                };
+                let mut accumulator = initial.clone();
+                for element in elements.iter() {
+                    accumulator = self.call_closure(function.clone(), vec![accumulator, element.clone()])
+                        .map_err(outside_loop)?;
+                }
+                Ok(accumulator)
+            }
+            (
+                "str" | "int" | "double" | "fixed" | "almost_equal" | "range" | "range_by" | "pretty" | "pretty_by"
+                    | "sqrt" | "sin" | "cos" | "tan" | "log" | "exp" | "compare" | "equals" | "index_of" | "is_empty" | "len" | "byte_len" | "map" | "filter" | "count_where" | "find" | "flatten" | "sort" | "sort_by" | "reduce" | "json" | "parse_json" | "to_bytes" | "from_bytes" | "read_file"
+                    | "lines_of" | "write_file" | "append_file" | "get_env",
+                _,
+            ) => {
+                Err(format!("`{}` cannot be called with {:?}", name, arguments).into())
+            }
+            _ => match self.natives.get(name) {
+                Some(function) => function(arguments.to_vec()),
+                None => Err(format!("No function `{}`", name).into()),
+            },
+        }
+    }
+}
+
+impl Default for Environment {
+    fn default() -> Self {
+        Environment::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::type_checker::TypeChecker;
+
+    /// Runs a program, giving back the environment it leaves behind.
+    fn run(source: &str) -> Environment {
+        let program = Parser::new(Lexer::new(source)).parse(&mut TypeChecker::new()).unwrap();
+        let mut environment = Environment::new();
+        environment.interpret(program).unwrap();
+        environment
+    }
+
+    /// Somewhere for `print` to write to that a test can read back.
+    #[derive(Clone, Default)]
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(bytes)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Checks and runs a program in an environment, giving back what it
+    /// printed, or its first error.
+    fn run_in(environment: &mut Environment, source: &str) -> Result<String, String> {
+        let output = Output::default();
+        environment.output = Box::new(output.clone());
+        let program = Parser::new(Lexer::new(source))
+            .parse(&mut TypeChecker::new())
+            .map_err(|errors| errors[0].to_string())?;
+        environment.interpret(program).map_err(|e| e.to_string())?;
+        let printed = output.0.take();
+        Ok(String::from_utf8(printed).unwrap())
+    }
+
+    fn output_of(source: &str) -> Result<String, String> {
+        run_in(&mut Environment::new(), source)
+    }
+
+    #[test]
+    fn doubles_survive_a_round_trip_through_strings() {
+        for d in ["3.14", "0.1", "123456.789", "1.0", "0.000001"] {
+            let environment = run(&format!("set d to {}\nset back to double (str d)", d));
+            match (environment.get("d"), environment.get("back")) {
+                (Some(Value::Double(d)), Some(Value::Double(back))) => assert_eq!(d.to_bits(), back.to_bits()),
+                other => panic!("{:?}", other),
+            }
+        }
+        // Doubles that no literal gives exactly
+        for d in [0.1 + 0.2, 1.0 / 3.0, 1e300, 2.5e-300, -2.5] {
+            assert_eq!(format_double(d).parse::<f64>(), Ok(d));
+        }
+        assert_eq!(format_double(3.0), "3.0");
+    }
+
+    #[test]
+    fn runaway_loops_stop_at_max_iterations() {
+        let program = Parser::new(Lexer::new("loop do print \"x\"")).parse(&mut TypeChecker::new()).unwrap();
+        let output = Output::default();
+        let mut environment = Environment::new();
+        environment.output = Box::new(output.clone());
+        environment.max_iterations = Some(100);
+        let error = environment.interpret(program).unwrap_err();
+        assert_eq!(error.to_string(), "Stopped `loop` after 100 iterations");
+        // The body runs every time up to the cap
+        assert_eq!(String::from_utf8(output.0.take()).unwrap(), "x\n".repeat(100));
+        // `iter` over an integer, a range or the lines of a file is capped the same way
+        let path = std::env::temp_dir().join(format!("clarice_runaway_{}.txt", std::process::id()));
+        std::fs::write(&path, "line\n".repeat(200)).unwrap();
+        let lines = format!("iter line in lines_of {:?} do pass end", path.to_str().unwrap());
+        for source in ["iter n in 100000000000 do pass end", "iter n in range 0 100000000000 do pass end", &lines] {
+            let mut environment = Environment::new();
+            environment.max_iterations = Some(100);
+            assert_eq!(run_in(&mut environment, source), Err("Stopped `iter` after 100 iterations".to_string()), "{}", source);
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn tail_calls_dont_grow_the_stack() {
+        let environment = run("
+            fn countdown(n) do
+                match n do
+                    case 0 then \"done\"
+                    otherwise then countdown (n - 1)
+                end
+            end
+            set result to countdown 100000
+        ");
+        assert!(matches!(environment.get("result"), Some(Value::String(s)) if &**s == "done"));
+    }
+
+    #[test]
+    fn huge_ranges_are_worked_out_as_they_go() {
+        let environment = run("
+            set seen to []
+            iter i in range 0 9223372036854775807 do
+                where i in [3] then break
+                set seen to seen + [i]
+            end
+            set last_in to 9223372036854775806 in range 0 9223372036854775807
+        ");
+        let seen = Value::List(Rc::new(vec![Value::Integer(0), Value::Integer(1), Value::Integer(2)]));
+        assert_eq!(environment.get("seen"), Some(&seen));
+        assert_eq!(environment.get("last_in"), Some(&Value::Boolean(true)));
+    }
+
+    #[test]
+    fn restoring_a_snapshot_undoes_changes() {
+        let mut environment = run("set xs to [1, 2]\nset name to \"a\"");
+        let snapshot = environment.snapshot();
+        let program = Parser::new(Lexer::new("set xs to xs + [3]\nset name to \"b\"\nset added to 1")).parse_only().unwrap();
+        environment.interpret(program).unwrap();
+        environment.restore(snapshot);
+        assert_eq!(environment.get("xs"), Some(&Value::List(Rc::new(vec![Value::Integer(1), Value::Integer(2)]))));
+        assert_eq!(environment.get("name"), Some(&Value::String("a".into())));
+        assert_eq!(environment.get("added"), None);
+    }
+
+    #[test]
+    fn dividing_a_double_by_zero_gives_infinity() {
+        let environment = run("
+            set up to str (1.0 / 0.0)
+            set down to str ((0.0 - 1.0) / 0.0)
+        ");
+        assert_eq!(environment.get("up"), Some(&Value::String("inf".into())));
+        assert_eq!(environment.get("down"), Some(&Value::String("-inf".into())));
+        let program = Parser::new(Lexer::new("set x to 1 / 0")).parse_only().unwrap();
+        assert_eq!(Environment::new().interpret(program).unwrap_err().to_string(), "Cannot divide 1 by zero");
+    }
+
+    #[test]
+    fn nan_isnt_equal_to_anything() {
+        let environment = run("
+            set n to 0.0 / 0.0
+            set shown to str n
+            set found to n in [n, 1.0]
+        ");
+        assert_eq!(environment.get("shown"), Some(&Value::String("nan".into())));
+        assert_eq!(environment.get("found"), Some(&Value::Boolean(false)));
+    }
+
+    #[test]
+    fn casts_convert_between_numbers_and_strings() {
+        let environment = run("
+            set truncated to 3.9 as integer
+            set negative to (0.0 - 3.9) as integer
+            set widened to 2 as double
+            set shown to [1.5, 2.0] as [string]
+            set parsed to \" 12 \" as integer
+        ");
+        assert_eq!(environment.get("truncated"), Some(&Value::Integer(3)));
+        assert_eq!(environment.get("negative"), Some(&Value::Integer(-3)));
+        assert_eq!(environment.get("widened"), Some(&Value::Double(2.0)));
+        let shown = Value::List(Rc::new(vec![Value::String("1.5".into()), Value::String("2.0".into())]));
+        assert_eq!(environment.get("shown"), Some(&shown));
+        assert_eq!(environment.get("parsed"), Some(&Value::Integer(12)));
+        let errors = Parser::new(Lexer::new("set x to [1] as integer")).parse(&mut TypeChecker::new()).unwrap_err();
+        assert_eq!(errors[0].to_string(), "line 1: Cannot convert List(Integer) to Integer");
+    }
+
+    #[test]
+    fn show_writes_booleans_as_yes_and_no_if_asked() {
+        let value = Value::List(Rc::new(vec![Value::Boolean(true), Value::Boolean(false), Value::String("true".into())]));
+        let mut environment = Environment::new();
+        assert_eq!(environment.show(&value), "[true, false, \"true\"]");
+        assert_eq!(run_in(&mut environment, "print 1 in [1]"), Ok("true\n".to_string()));
+        environment.yes_no = true;
+        assert_eq!(environment.show(&value), "[yes, no, \"true\"]");
+        assert_eq!(run_in(&mut environment, "print 1 in [1]"), Ok("yes\n".to_string()));
+    }
+
+    #[test]
+    fn indexes_outside_a_list_are_errors() {
+        let list = Value::List(Rc::new(vec![Value::Integer(10), Value::Integer(20), Value::Integer(30)]));
+        let at = |index: i64| index_into(&list, &Value::Integer(index));
+        assert_eq!(at(2), Ok(Value::Integer(30)));
+        assert_eq!(at(-3), Ok(Value::Integer(10)));
+        assert_eq!(at(3), Err("index 3 out of bounds for list of length 3".to_string()));
+        assert_eq!(at(i64::MAX), Err(format!("index {} out of bounds for list of length 3", i64::MAX)));
+        assert_eq!(at(-4), Err("index -4 out of bounds for list of length 3".to_string()));
+        assert_eq!(at(i64::MIN), Err(format!("index {} out of bounds for list of length 3", i64::MIN)));
+    }
+
+    #[test]
+    fn indexes_outside_strings_and_ranges_are_errors() {
+        let mut environment = Environment::new();
+        assert_eq!(run_in(&mut environment, "print \"héllo\"[1]"), Ok("é\n".to_string()));
+        assert_eq!(run_in(&mut environment, "print \"héllo\"[5]"), Err("index 5 out of bounds for string of length 5".to_string()));
+        assert_eq!(
+            run_in(&mut environment, "print (range 0 9223372036854775807)[0 - 1]"),
+            Ok("9223372036854775806\n".to_string())
+        );
+        assert_eq!(
+            run_in(&mut environment, "print (range_by 0 10 3)[4]"),
+            Err("index 4 out of bounds for list of length 4".to_string())
+        );
+    }
+
+    #[test]
+    fn dot_dot_joins_strings_and_numbers() {
+        assert_eq!(output_of("print \"a\" .. 1 .. 2.5"), Ok("a12.5\n".to_string()));
+        assert_eq!(output_of("print \"n is \" .. 1 + 2"), Ok("n is 3\n".to_string()));
+        assert_eq!(output_of("print \"a\" .. [1]"), Err("line 1: Cannot join String and List(Integer) with `..`".to_string()));
+    }
+
+    #[test]
+    fn strict_coercion_only_joins_strings() {
+        let mut type_checker = TypeChecker::new();
+        type_checker.strict_coercion = true;
+        let errors = Parser::new(Lexer::new("print \"a\" .. str 1\nprint \"a\" .. 1")).parse(&mut type_checker).unwrap_err();
+        assert_eq!(
+            errors.iter().map(ToString::to_string).collect::<Vec<_>>(),
+            ["line 2: `..` only joins strings with `--strict-coercion` - use `str` to turn Integer into a string"]
+        );
+
+        let mut environment = Environment::new();
+        environment.strict_coercion = true;
+        let program = Parser::new(Lexer::new("print 1 .. \"a\"")).parse_only().unwrap();
+        assert_eq!(
+            environment.interpret(program).unwrap_err().to_string(),
+            "`..` only joins strings with strict coercion on - use `str` to turn Integer(1) into a string"
+        );
+    }
+
+    #[test]
+    fn break_can_leave_an_outer_loop_by_name() {
+        let source = "
+            set pairs to 0
+            loop as outer do
+                iter i in 3 do
+                    iter j in 3 do
+                        where j in [2] then break outer
+                        set pairs to pairs + 1
+                    end
+                end
+            end
+            print pairs
+        ";
+        assert_eq!(output_of(source), Ok("2\n".to_string()));
+        assert_eq!(output_of("loop do break nowhere end"), Err("line 1: There's no loop called `nowhere` for `break` to leave".to_string()));
+    }
+
+    #[test]
+    fn byte_len_counts_bytes() {
+        assert_eq!(output_of("print byte_len \"naïve\"\nprint \"naïve\"[4]"), Ok("6\ne\n".to_string()));
+    }
+
+    /// Runs a program without checking it first, as `--no-typecheck` does.
+    fn run_unchecked(source: &str) -> Result<String, String> {
+        let output = Output::default();
+        let mut environment = Environment::new();
+        environment.output = Box::new(output.clone());
+        let program = Parser::new(Lexer::new(source))
+            .parse_only()
+            .map_err(|errors| errors[0].to_string())?;
+        environment.interpret(program).map_err(|e| e.to_string())?;
+        let printed = output.0.take();
+        Ok(String::from_utf8(printed).unwrap())
+    }
+
+    #[test]
+    fn closures_remember_their_variables() {
+        let source = "
+            fn adder(x) do
+                fn add(y) do x + y end
+                add
+            end
+            set add_two to adder 2
+            print add_two 3
+        ";
+        assert_eq!(output_of(source), Ok("5\n".to_string()));
+    }
+
+    #[test]
+    fn functions_dont_see_their_callers_variables() {
+        let source = "
+            fn show(a) do secret end
+            fn caller(x) do
+                set secret to 1
+                show x
+            end
+            print caller 0
+        ";
+        assert_eq!(run_unchecked(source), Err("No variable `secret` - use `with` or `set` to define it".to_string()));
+    }
+
+    /// A program with `where` blocks nested `depth` deep, printing 1 at the bottom.
+    fn nested_wheres(depth: usize) -> String {
+        format!("set x to 1\n{}print x\n{}", "where x in [1] do\n".repeat(depth), "end\n".repeat(depth))
+    }
+
+    /// Runs `f` on a thread with as much stack as programs are run with.
+    fn on_program_stack<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> T {
+        std::thread::Builder::new().stack_size(STACK_SIZE).spawn(f).unwrap().join().unwrap()
+    }
+
+    #[test]
+    fn thousands_of_nested_blocks_run() {
+        assert_eq!(on_program_stack(|| output_of(&nested_wheres(MAX_NESTING - 2))), Ok("1\n".to_string()));
+    }
+
+    #[test]
+    fn nesting_too_deeply_is_an_error() {
+        let too_deep = format!(
+            "line {}: This is nested more than {} levels deep - blocks, brackets, casts and indexes all count",
+            MAX_NESTING, MAX_NESTING
+        );
+        assert_eq!(on_program_stack(|| output_of(&nested_wheres(MAX_NESTING + 1))), Err(too_deep));
+        let parentheses = format!("print {}1{}", "(".repeat(MAX_NESTING + 1), ")".repeat(MAX_NESTING + 1));
+        assert!(on_program_stack(move || output_of(&parentheses)).unwrap_err().contains("nested more than"));
+    }
+
+    #[test]
+    fn each_statement_that_cant_be_parsed_is_reported() {
+        let errors = Parser::new(Lexer::new("set 1 to 2\nend\nwith x = 1\nprint 3")).parse_only().unwrap_err();
+        let messages: Vec<String> = errors.iter().map(ToString::to_string).collect();
+        assert_eq!(messages, [
+            "line 1: Expected identifier after 'set' keyword, got IntegerLiteral(1)",
+            "line 2: `end` can't start a statement",
+            "line 3: Expected 'as' after identifier, got Operator(\"=\") - `=` is not used for variable assignment in Clarice. Use `as` instead.",
+        ]);
+    }
+
+    #[test]
+    fn long_chains_of_operators_run() {
+        let sum = format!("set n to 1\nprint n{}", " + n".repeat(100_000));
+        assert_eq!(output_of(&sum), Ok("100001\n".to_string()));
+        let joined = format!("set s to \"a\"\nprint byte_len (s{})", " .. s".repeat(100_000));
+        assert_eq!(output_of(&joined), Ok("100001\n".to_string()));
+        let constant = format!("print 1{}", " + 1".repeat(100_000));
+        assert_eq!(output_of(&constant), Ok("100001\n".to_string()));
+    }
+
+    #[test]
+    fn strings_repeat_up_to_a_maximum_length() {
+        assert_eq!(output_of("print \"ab\" * 3 then print 2 * \"c\""), Ok("ababab\ncc\n".to_string()));
+        assert_eq!(output_of("print \"ab\" * 0"), Ok("\n".to_string()));
+        let too_long = format!("Repeating a string 9223372036854775807 times would make it too long (the most is {} bytes)", MAX_STRING_LENGTH);
+        assert_eq!(output_of("print \"ab\" * 9223372036854775807"), Err(too_long));
+        assert_eq!(output_of("print \"ab\" * (0 - 1)"), Err("Cannot repeat a string -1 times".to_string()));
+    }
+
+    #[test]
+    fn comprehensions_over_an_integer_count_up_to_it() {
+        assert_eq!(output_of("print [x * 2 for x in 3]"), Ok("[0, 2, 4]\n".to_string()));
+        let too_many = format!(
+            "range 0 100000000000 has 100000000000 elements, which is too many to make into a list (the most is {})",
+            MAX_LIST_LENGTH
+        );
+        assert_eq!(output_of("print [x for x in 100000000000]"), Err(too_many));
+    }
+
+    #[test]
+    fn or_defaults_void_and_undefined_values() {
+        assert_eq!(output_of("set maybe to 5\nprint maybe or 0"), Ok("5\n".to_string()));
+        assert_eq!(output_of("print get_env \"NO_SUCH_VARIABLE\" or \"unset\""), Ok("unset\n".to_string()));
+        assert_eq!(output_of("print nope or 0"), Ok("0\n".to_string()));
+        assert_eq!(run_unchecked("print nope or 0"), Ok("0\n".to_string()));
+        // Only the value being defaulted can be undefined
+        assert!(output_of("print 0 or nope").unwrap_err().contains("Undefined variable `nope`"));
+    }
+
+    /// Runs a program, giving back the status code it used `exit` with.
+    fn exit_code(source: &str) -> Option<i32> {
+        let program = Parser::new(Lexer::new(source)).parse(&mut TypeChecker::new()).unwrap();
+        let mut environment = Environment::new();
+        environment.output = Box::new(Output::default());
+        environment.interpret(program).err()?.exit_code
+    }
+
+    #[test]
+    fn exit_stops_the_program_with_a_status_code() {
+        assert_eq!(exit_code("exit"), Some(0));
+        assert_eq!(exit_code("print 1\nexit 3\nprint 2"), Some(3));
+        // An exit gets out of functions, loops and built-ins that call functions
+        assert_eq!(exit_code("fn stop(x: integer) do exit x end\nloop do print map stop [7] end"), Some(7));
+        assert_eq!(exit_code("print 1"), None);
+    }
+
+    #[test]
+    fn exit_codes_have_to_fit_in_32_bits() {
+        assert_eq!(
+            output_of("exit 4294967296"),
+            Err("`exit` can't use 4294967296 as a status code, as it doesn't fit in 32 bits".to_string())
+        );
+        assert_eq!(exit_code("exit 0 - 2147483648"), Some(i32::MIN));
+    }
+
+    #[test]
+    fn doubles_only_become_integers_if_they_fit() {
+        assert_eq!(output_of("print 3.9 as integer\nprint (0.0 - 3.9) as integer"), Ok("3\n-3\n".to_string()));
+        assert_eq!(output_of("print 9223372036854774784.0 as integer"), Ok("9223372036854774784\n".to_string()));
+        assert_eq!(output_of("print (0.0 - 9223372036854775808.0) as integer"), Ok("-9223372036854775808\n".to_string()));
+        let too_big = |d: &str| format!("Cannot convert {} to an integer, as it's too big (the biggest is {})", d, i64::MAX);
+        assert_eq!(output_of("print 1e300 as integer"), Err(too_big("1e300")));
+        assert_eq!(output_of("print 9223372036854775808.0 as integer"), Err(too_big("9.223372036854776e18")));
+        assert_eq!(output_of("print (0.0 - 9223372036854777856.0) as integer"), Err(too_big("-9.223372036854778e18")));
+        assert_eq!(output_of("print int (1.0 / 0.0)"), Err("Cannot convert inf to an integer".to_string()));
+        assert_eq!(output_of("print (0.0 / 0.0) as integer"), Err("Cannot convert nan to an integer".to_string()));
+    }
+
+    #[test]
+    fn len_counts_characters_and_byte_len_counts_bytes() {
+        assert_eq!(output_of("print len \"naïve\"\nprint byte_len \"naïve\""), Ok("5\n6\n".to_string()));
+        assert_eq!(output_of("print len [1, 2, 3]\nprint len {\"a\": 1}\nprint len (range_by 0 10 3)"), Ok("3\n1\n4\n".to_string()));
+        assert_eq!(output_of("print len (range 0 9223372036854775807)"), Ok("9223372036854775807\n".to_string()));
+        assert_eq!(
+            output_of("print len (range (0 - 9223372036854775807) 9223372036854775807)"),
+            Err("range -9223372036854775807 9223372036854775807 has too many elements to count".to_string())
+        );
+        assert_eq!(output_of("print len 5"), Err("line 1: `len` expects a list, map or string, got Integer".to_string()));
+    }
+
+    #[test]
+    fn is_empty_works_on_the_lines_of_a_file() {
+        let path = std::env::temp_dir().join(format!("clarice_is_empty_{}.txt", std::process::id()));
+        let path = path.to_str().unwrap();
+        let source = format!(
+            "write_file {path:?} \"\"\nprint is_empty (lines_of {path:?})\nwrite_file {path:?} \"\\n\"\nprint is_empty (lines_of {path:?})"
+        );
+        let result = output_of(&source);
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(result, Ok("true\nfalse\n".to_string()));
+        assert!(output_of("print is_empty (lines_of \"no/such/file\")").unwrap_err().starts_with("Couldn't read no/such/file"));
+    }
+
+    #[test]
+    fn a_lambda_can_be_a_statement_of_its_own() {
+        let source = "
+            fn make(n) do fn(x) do x + n end end
+            set add to make 2
+            print add 3
+        ";
+        assert_eq!(output_of(source), Ok("5\n".to_string()));
+        // With a name, `fn` still declares a function
+        assert_eq!(output_of("fn f(x) do x end\nprint f 1"), Ok("1\n".to_string()));
+    }
+
+    #[test]
+    fn where_runs_only_if_every_condition_holds() {
+        let source = "
+            set xs to [1, 2]
+            where 1 in xs, 2 in xs then print \"both\"
+            where 1 in xs, 3 in xs do
+                print \"wrong\"
+            otherwise do
+                print \"not both\"
+            end
+        ";
+        assert_eq!(output_of(source), Ok("both\nnot both\n".to_string()));
+    }
+
+    #[test]
+    fn where_stops_at_the_first_condition_that_fails() {
+        // Working out `xs[5]` would be an error, so it mustn't be reached
+        assert_eq!(output_of("set xs to [1, 2]\nwhere 3 in xs, xs[5] in xs then print \"wrong\""), Ok(String::new()));
+        let reached = output_of("set xs to [1, 2]\nwhere 1 in xs, xs[5] in xs then print \"wrong\"");
+        assert_eq!(reached, Err("index 5 out of bounds for list of length 2".to_string()));
+    }
+
+    #[test]
+    fn every_condition_of_where_is_checked() {
+        let error = "line 2: A condition has to be a boolean, got Integer - pass `--truthiness` to let any value be a condition";
+        assert_eq!(output_of("set xs to [1, 2]\nwhere 1 in xs, 5 then print \"wrong\""), Err(error.to_string()));
+    }
+
+    #[test]
+    fn comparisons_give_booleans() {
+        let source = "
+            set x to 5
+            print if x > 0 then \"positive\" else \"not positive\"
+            print [x < 5, x <= 5, x >= 6, \"a\" < \"b\", [1, 2] == [1, 2], x != 5]
+            # Rounding error makes these differ, which is what almost_equal is for
+            print [0.1 + 0.2 == 0.3, almost_equal (0.1 + 0.2) 0.3]
+            # NaN isn't equal to, less than or greater than anything, itself included
+            set n to 0.0 / 0.0
+            print [n == n, n != n, n < 1.0, n >= 1.0]
+        ";
+        let expected = "positive\n[false, true, false, true, true, false]\n[false, true]\n[false, true, false, false]\n";
+        assert_eq!(output_of(source), Ok(expected.to_string()));
+        assert_eq!(output_of("print 1 < \"a\""), Err("line 1: `<` needs two numbers or two strings, got Integer and String".to_string()));
+        assert!(output_of("print 1 < 2 < 3").is_err());
+    }
+}
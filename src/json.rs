@@ -0,0 +1,340 @@
+// json.rs - Reading and writing JSON for the Clarice programming language
+
+use std::iter::Peekable;
+use std::rc::Rc;
+use std::str::CharIndices;
+
+use crate::interpreter::{force_list, format_double, Key, Map, Value};
+
+/// Writes a value as JSON, with no spaces. A range is written as the list
+/// it stands for, a map as an object, and void as `null`. Functions, and
+/// doubles like `nan` that JSON has no way to write, are errors.
+/// 
+/// Example:
+/// ```clarice
+/// print json [["a", "b"], ["c"]]
+/// # -> [["a","b"],["c"]]
+/// ```
+pub fn write(value: &Value, output: &mut String) -> Result<(), String> {
+    match value {
+        Value::Integer(i) => output.push_str(&i.to_string()),
+        Value::Double(d) if d.is_finite() => output.push_str(&format_double(*d)),
+        Value::Boolean(b) => output.push_str(&b.to_string()),
+        Value::Void => output.push_str("null"),
+        Value::String(s) => write_string(s, output),
+        Value::List(elements) => {
+            output.push('[');
+            for (i, element) in elements.iter().enumerate() {
+                if i > 0 {
+                    output.push(',');
+                }
+                write(element, output)?;
+            }
+            output.push(']');
+        }
+        Value::Map(map) => {
+            output.push('{');
+            for (i, (key, value)) in map.iter().enumerate() {
+                if i > 0 {
+                    output.push(',');
+                }
+                // JSON keys are always strings
+                write_string(&Value::from(key).to_string(), output);
+                output.push(':');
+                write(value, output)?;
+            }
+            output.push('}');
+        }
+        Value::Range { .. } | Value::Lines(_) => write(&force_list(value)?, output)?,
+        Value::Double(_) | Value::Closure(_) => return Err(format!("{} can't be written as JSON", value)),
+    }
+    Ok(())
+}
+
+fn write_string(s: &str, output: &mut String) {
+    output.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => output.push_str("\\\""),
+            '\\' => output.push_str("\\\\"),
+            '\n' => output.push_str("\\n"),
+            '\r' => output.push_str("\\r"),
+            '\t' => output.push_str("\\t"),
+            c if c.is_control() => output.push_str(&format!("\\u{:04x}", c as u32)),
+            c => output.push(c),
+        }
+    }
+    output.push('"');
+}
+
+/// Reads a JSON value. Numbers without a fraction or exponent become
+/// integers if they fit, and other numbers become doubles. Arrays become
+/// lists, objects become maps with string keys, and `null` becomes void,
+/// which shows as `void` in a list or map and can be given a default with
+/// `or`. Void can't be bound to a variable, so `null` on its own can only be
+/// used straight away, as in `parse_json text or 0`.
+/// 
+/// Example:
+/// ```clarice
+/// print parse_json """[1, 2.5, "three", null]"""
+/// # -> [1, 2.5, "three", void]
+/// ```
+pub fn parse(text: &str) -> Result<Value, String> {
+    let mut reader = Reader { chars: text.char_indices().peekable(), depth: 0 };
+    let value = reader.value()?;
+    reader.skip_whitespace();
+    match reader.chars.next() {
+        None => Ok(value),
+        Some((position, c)) => Err(format!("Invalid JSON at character {}: unexpected {:?} after the value", position, c)),
+    }
+}
+
+/// How deeply arrays and objects can nest inside each other. Reading them
+/// recurses, so this keeps deeply nested text from running out of stack.
+const MAX_DEPTH: usize = 1000;
+
+/// Goes through JSON text a character at a time.
+struct Reader<'a> {
+    chars: Peekable<CharIndices<'a>>,
+    depth: usize, // How many arrays and objects the next character is inside
+}
+
+impl Reader<'_> {
+    fn skip_whitespace(&mut self) {
+        while self.chars.next_if(|(_, c)| matches!(c, ' ' | '\t' | '\n' | '\r')).is_some() {}
+    }
+
+    /// An error about the next character, or the end of the text.
+    fn unexpected(&mut self, expected: &str) -> String {
+        match self.chars.peek() {
+            Some((position, c)) => format!("Invalid JSON at character {}: expected {}, got {:?}", position, expected, c),
+            None => format!("Invalid JSON: expected {}, but the text ended", expected),
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        match self.chars.next_if(|(_, c)| *c == expected) {
+            Some(_) => Ok(()),
+            None => Err(self.unexpected(&format!("{:?}", expected))),
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        self.skip_whitespace();
+        match self.chars.peek().copied() {
+            Some((position, _)) if self.depth == MAX_DEPTH => Err(format!(
+                "Invalid JSON at character {}: arrays and objects can only nest {} deep",
+                position, MAX_DEPTH
+            )),
+            Some((_, '[')) => self.nested(Self::array),
+            Some((_, '{')) => self.nested(Self::object),
+            Some((_, '"')) => Ok(Value::String(self.string()?.into())),
+            Some((_, 't')) => self.word("true", Value::Boolean(true)),
+            Some((_, 'f')) => self.word("false", Value::Boolean(false)),
+            Some((_, 'n')) => self.word("null", Value::Void),
+            Some((_, '-' | '0'..='9')) => self.number(),
+            _ => Err(self.unexpected("a value")),
+        }
+    }
+
+    /// Reads an array or object one level deeper.
+    fn nested(&mut self, read: fn(&mut Self) -> Result<Value, String>) -> Result<Value, String> {
+        self.depth += 1;
+        let value = read(self);
+        self.depth -= 1;
+        value
+    }
+
+    fn word(&mut self, word: &str, value: Value) -> Result<Value, String> {
+        for expected in word.chars() {
+            self.expect(expected)?;
+        }
+        Ok(value)
+    }
+
+    /// Reads a number, which JSON writes as an optional `-`, then either `0`
+    /// or digits not starting with `0`, then an optional fraction and
+    /// exponent.
+    fn number(&mut self) -> Result<Value, String> {
+        let mut number = String::new();
+        self.take(&mut number, |c| c == '-');
+        if !self.take(&mut number, |c| c == '0') && !self.take_digits(&mut number) {
+            return Err(self.unexpected("a digit"));
+        }
+        if self.take(&mut number, |c| c == '.') && !self.take_digits(&mut number) {
+            return Err(self.unexpected("a digit after the decimal point"));
+        }
+        if self.take(&mut number, |c| matches!(c, 'e' | 'E')) {
+            self.take(&mut number, |c| matches!(c, '+' | '-'));
+            if !self.take_digits(&mut number) {
+                return Err(self.unexpected("a digit in the exponent"));
+            }
+        }
+        if !number.contains(['.', 'e', 'E']) {
+            if let Ok(i) = number.parse() {
+                return Ok(Value::Integer(i));
+            }
+        }
+        match number.parse::<f64>() {
+            Ok(d) if d.is_finite() => Ok(Value::Double(d)),
+            _ => Err(format!("Invalid JSON: {} is too big to be a double", number)),
+        }
+    }
+
+    /// Adds the next character to `number` if it's one `accept`s, giving back
+    /// whether it was.
+    fn take(&mut self, number: &mut String, accept: impl Fn(char) -> bool) -> bool {
+        match self.chars.next_if(|(_, c)| accept(*c)) {
+            Some((_, c)) => {
+                number.push(c);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Adds any digits that come next to `number`, giving back whether there
+    /// were any.
+    fn take_digits(&mut self, number: &mut String) -> bool {
+        let mut any = false;
+        while self.take(number, |c| c.is_ascii_digit()) {
+            any = true;
+        }
+        any
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut string = String::new();
+        loop {
+            match self.chars.next() {
+                Some((_, '"')) => return Ok(string),
+                Some((_, '\\')) => {
+                    let escaped = match self.chars.next() {
+                        Some((_, '"')) => '"',
+                        Some((_, '\\')) => '\\',
+                        Some((_, '/')) => '/',
+                        Some((_, 'b')) => '\u{8}',
+                        Some((_, 'f')) => '\u{c}',
+                        Some((_, 'n')) => '\n',
+                        Some((_, 'r')) => '\r',
+                        Some((_, 't')) => '\t',
+                        Some((_, 'u')) => self.unicode_escape()?,
+                        Some((position, c)) => {
+                            return Err(format!("Invalid JSON at character {}: unknown escape \\{}", position, c))
+                        }
+                        None => return Err("Invalid JSON: a string has no closing quote".to_string()),
+                    };
+                    string.push(escaped);
+                }
+                Some((_, c)) => string.push(c),
+                None => return Err("Invalid JSON: a string has no closing quote".to_string()),
+            }
+        }
+    }
+
+    /// Reads the rest of a `\u` escape, including the second half of a
+    /// surrogate pair for characters outside the Basic Multilingual Plane.
+    fn unicode_escape(&mut self) -> Result<char, String> {
+        let high = self.hex_digits()?;
+        let code = if (0xD800..0xDC00).contains(&high) {
+            self.expect('\\')?;
+            self.expect('u')?;
+            let low = self.hex_digits()?;
+            0x10000 + ((high - 0xD800) << 10) + low.wrapping_sub(0xDC00)
+        }
+        else {
+            high
+        };
+        char::from_u32(code).ok_or_else(|| format!("Invalid JSON: \\u{:04x} isn't a character", code))
+    }
+
+    fn hex_digits(&mut self) -> Result<u32, String> {
+        let mut code = 0;
+        for _ in 0..4 {
+            let digit = self.chars.peek().and_then(|(_, c)| c.to_digit(16));
+            match digit {
+                Some(digit) => {
+                    self.chars.next();
+                    code = code * 16 + digit;
+                }
+                None => return Err(self.unexpected("a hex digit")),
+            }
+        }
+        Ok(code)
+    }
+
+    fn array(&mut self) -> Result<Value, String> {
+        self.expect('[')?;
+        let mut elements = Vec::new();
+        self.skip_whitespace();
+        if self.chars.next_if(|(_, c)| *c == ']').is_none() {
+            loop {
+                elements.push(self.value()?);
+                self.skip_whitespace();
+                if self.chars.next_if(|(_, c)| *c == ']').is_some() {
+                    break;
+                }
+                self.expect(',')?;
+            }
+        }
+        Ok(Value::List(Rc::new(elements)))
+    }
+
+    fn object(&mut self) -> Result<Value, String> {
+        self.expect('{')?;
+        let mut map = Map::default();
+        self.skip_whitespace();
+        if self.chars.next_if(|(_, c)| *c == '}').is_none() {
+            loop {
+                self.skip_whitespace();
+                let key = self.string()?;
+                self.skip_whitespace();
+                self.expect(':')?;
+                map.insert(Key::String(key), self.value()?);
+                self.skip_whitespace();
+                if self.chars.next_if(|(_, c)| *c == '}').is_some() {
+                    break;
+                }
+                self.expect(',')?;
+            }
+        }
+        Ok(Value::Map(Rc::new(map)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn null_is_void_and_shows_as_void() {
+        let value = parse(r#"[1, null, {"a": null}]"#).unwrap();
+        assert_eq!(value.to_string(), r#"[1, void, {"a": void}]"#);
+        let mut output = String::new();
+        write(&value, &mut output).unwrap();
+        assert_eq!(output, r#"[1,null,{"a":null}]"#);
+        assert_eq!(parse("null"), Ok(Value::Void));
+    }
+
+    #[test]
+    fn numbers_follow_the_json_grammar() {
+        assert_eq!(parse("[0, -12, 0.5, -1.5e2, 2E-1]").unwrap().to_string(), "[0, -12, 0.5, -150.0, 0.2]");
+        assert_eq!(parse("01"), Err("Invalid JSON at character 1: unexpected '1' after the value".to_string()));
+        assert_eq!(parse("1."), Err("Invalid JSON: expected a digit after the decimal point, but the text ended".to_string()));
+        assert_eq!(parse("-"), Err("Invalid JSON: expected a digit, but the text ended".to_string()));
+        assert_eq!(parse("1e+"), Err("Invalid JSON: expected a digit in the exponent, but the text ended".to_string()));
+        assert_eq!(parse("[1e999]"), Err("Invalid JSON: 1e999 is too big to be a double".to_string()));
+        assert_eq!(parse("-1e999"), Err("Invalid JSON: -1e999 is too big to be a double".to_string()));
+    }
+
+    #[test]
+    fn deeply_nested_arrays_are_an_error() {
+        let deepest = format!("{}{}", "[".repeat(MAX_DEPTH), "]".repeat(MAX_DEPTH));
+        assert!(parse(&deepest).is_ok());
+        let too_deep = format!("{}{}", "[".repeat(200_000), "]".repeat(200_000));
+        let error = format!("Invalid JSON at character {}: arrays and objects can only nest {} deep", MAX_DEPTH, MAX_DEPTH);
+        assert_eq!(parse(&too_deep), Err(error));
+        assert!(parse(&"{\"a\": ".repeat(200_000)).is_err());
+    }
+}
@@ -1,20 +1,137 @@
 // lexer.rs - Lexer for the Clarice programming language
 
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Keyword {
+    With,
+    Set,
+    As,
+    To,
+    Then,
+    Do,
+    End,
+    Print,
+    Where,
+    Otherwise,
+    Loop,
+    While,
+    Iter,
+    In,
+    Exit,
+    Match,
+    Case,
+    Fn,
+    Break,
+    Next,
+    If,
+    Else,
+    Import,
+    Pass,
+    For,
+    Or,
+}
+
+impl Keyword {
+    /// The keyword spelled by `word`, if it is one.
+    pub fn from_word(word: &str) -> Option<Keyword> {
+        let keyword = match word {
+            "with" => Keyword::With,
+            "set" => Keyword::Set,
+            "as" => Keyword::As,
+            "to" => Keyword::To,
+            "then" => Keyword::Then,
+            "do" => Keyword::Do,
+            "end" => Keyword::End,
+            "print" => Keyword::Print,
+            "where" => Keyword::Where,
+            "otherwise" => Keyword::Otherwise,
+            "loop" => Keyword::Loop,
+            "while" => Keyword::While,
+            "iter" => Keyword::Iter,
+            "in" => Keyword::In,
+            "exit" => Keyword::Exit,
+            "match" => Keyword::Match,
+            "case" => Keyword::Case,
+            "fn" => Keyword::Fn,
+            "break" => Keyword::Break,
+            "next" => Keyword::Next,
+            "if" => Keyword::If,
+            "else" => Keyword::Else,
+            "import" => Keyword::Import,
+            "pass" => Keyword::Pass,
+            "for" => Keyword::For,
+            "or" => Keyword::Or,
+            _ => return None,
+        };
+        Some(keyword)
+    }
+
+    pub fn word(self) -> &'static str {
+        match self {
+            Keyword::With => "with",
+            Keyword::Set => "set",
+            Keyword::As => "as",
+            Keyword::To => "to",
+            Keyword::Then => "then",
+            Keyword::Do => "do",
+            Keyword::End => "end",
+            Keyword::Print => "print",
+            Keyword::Where => "where",
+            Keyword::Otherwise => "otherwise",
+            Keyword::Loop => "loop",
+            Keyword::While => "while",
+            Keyword::Iter => "iter",
+            Keyword::In => "in",
+            Keyword::Exit => "exit",
+            Keyword::Match => "match",
+            Keyword::Case => "case",
+            Keyword::Fn => "fn",
+            Keyword::Break => "break",
+            Keyword::Next => "next",
+            Keyword::If => "if",
+            Keyword::Else => "else",
+            Keyword::Import => "import",
+            Keyword::Pass => "pass",
+            Keyword::For => "for",
+            Keyword::Or => "or",
+        }
+    }
+}
+
+impl std::fmt::Display for Keyword {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.word())
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum Token {
-    Keyword(String), // "with", "as", "to", "set", "where", "otherwise", "iter", etc.
+    Keyword(Keyword), // with, as, to, set, where, otherwise, iter, etc.
     Identifier(String), // "x", "y", "z", "tomato", "celery", "avocado", etc.
     IntegerLiteral(i64), // 1, 2, 3
+    DoubleLiteral(f64), // 3.14, 0.5
     StringLiteral(String), // "hello, world!"
     Operator(String), // "+", "-", "*", "/", etc.
     Separator(String),
-    EOF,
+    Comment(String), // "# a comment", without the "#"
+    Invalid(String), // Something that isn't a token, with a message saying why
+    Eof,
 }
 
 pub struct Lexer<'a> {
     input: &'a str,
-    position: usize,
+    position: usize, // Byte offset of the character after `current_char`
     current_char: Option<char>,
+    line: usize,
+    /// The line that the most recent token started on, counting from 1.
+    pub token_line: usize,
+    /// Whether the most recent token was the first on its line. A line ending
+    /// in `\` carries on onto the next one, so the token after it isn't.
+    pub token_starts_line: bool,
+    /// Whether there was any space (or a comment) just before the most recent
+    /// token, which tells `xs[0]` (indexing) apart from `f xs [0]`.
+    pub token_after_space: bool,
+    /// Gives comments as `Token::Comment`s instead of skipping them.
+    preserve_comments: bool,
 }
 
 impl<'a> Lexer<'a> {
@@ -23,19 +140,39 @@ impl<'a> Lexer<'a> {
             input,
             position: 0,
             current_char: None,
+            line: 1,
+            token_line: 1,
+            token_starts_line: true,
+            token_after_space: false,
+            preserve_comments: false,
         };
         lexer.advance();
         lexer
     }
 
+    /// Makes a lexer that keeps comments as `Token::Comment`s, for tools like
+    /// the formatter that need to put them back. The parser turns them into
+    /// `StatementKind::Comment`s, which only the formatter uses.
+    pub fn with_comments(input: &'a str) -> Self {
+        let mut lexer = Lexer::new(input);
+        lexer.preserve_comments = true;
+        lexer
+    }
+
     fn advance(&mut self) {
-        self.current_char = self.input.chars().nth(self.position);
-        self.position += 1;
+        if self.current_char == Some('\n') {
+            self.line += 1;
+        }
+        self.current_char = self.input[self.position..].chars().next();
+        self.position += self.current_char.map_or(1, char::len_utf8);
     }
 
     fn skip_whitespace(&mut self) {
         while let Some(c) = self.current_char {
             if c.is_whitespace() {
+                if c == '\n' {
+                    self.token_starts_line = true;
+                }
                 self.advance();
             }
             else {
@@ -44,51 +181,140 @@ impl<'a> Lexer<'a> {
         }
     }
 
+    /// Skips a comment, from `#` to the end of the line, giving back its text.
+    fn skip_comment(&mut self) -> String {
+        let mut comment = String::new();
+        self.advance(); // Skip "#"
+        while let Some(c) = self.current_char {
+            if c == '\n' {
+                break;
+            }
+            comment.push(c);
+            self.advance();
+        }
+        comment
+    }
+
+    /// The first character after the most recent token that isn't
+    /// whitespace, so the parser can look one step further ahead, e.g. to
+    /// tell a lambda `fn(x)` apart from a function `fn name(x)`.
+    pub fn next_char(&self) -> Option<char> {
+        self.current_char
+            .into_iter()
+            .chain(self.input.get(self.position..).unwrap_or("").chars())
+            .find(|c| !c.is_whitespace())
+    }
+
     fn peek(&self) -> Option<char> {
-        self.input.chars().nth(self.position)
+        self.input.get(self.position..)?.chars().next()
     }
 
     pub fn get_next_token(&mut self) -> Token {
+        self.token_starts_line = false;
+        self.token_after_space = false;
         while let Some(c) = self.current_char {
             if c.is_whitespace() {
                 self.skip_whitespace();
+                self.token_after_space = true;
+                continue;
+            }
+            // A `\` at the end of a line joins the next line onto it
+            if c == '\\' && matches!(self.peek(), Some('\n' | '\r')) {
+                self.advance(); // Skip "\"
+                if self.current_char == Some('\r') {
+                    self.advance();
+                }
+                if self.current_char == Some('\n') {
+                    self.advance();
+                }
+                self.token_after_space = true;
+                continue;
+            }
+            // Some editors start files with a byte order mark, which means nothing here
+            if c == '\u{FEFF}' {
+                self.advance();
+                continue;
+            }
+            self.token_line = self.line;
+
+            if c == '#' {
+                let comment = self.skip_comment();
+                if self.preserve_comments {
+                    return Token::Comment(comment);
+                }
+                self.token_after_space = true;
                 continue;
             }
             
-            if c.is_digit(10) {
-                return self.tokenize_integer();
+            if c.is_ascii_digit() {
+                return self.tokenize_number();
             }
             else if c.is_alphabetic() {
                 return self.tokenize_identifier_or_keyword();
             }
             else {
                 let token = match c {
-                    '+' | '-' | '*' | '/' | '=' => {
+                    '+' | '-' | '*' | '/' => {
                         self.advance();
                         Token::Operator(c.to_string())
                     },
+                    // `==`, `<=` and `>=` are one operator each, and `=`, `<` and `>` on their own are others
+                    '=' | '<' | '>' => {
+                        self.advance();
+                        if self.current_char == Some('=') {
+                            self.advance();
+                            Token::Operator(format!("{}=", c))
+                        }
+                        else {
+                            Token::Operator(c.to_string())
+                        }
+                    },
+                    // `!=` is one operator, and `!` on its own is another
+                    '!' => {
+                        self.advance();
+                        if self.current_char == Some('=') {
+                            self.advance();
+                            Token::Operator("!=".to_string())
+                        }
+                        else {
+                            Token::Operator("!".to_string())
+                        }
+                    },
+                    // `..` is one operator, but `.` on its own stays a separator
+                    '.' if self.peek() == Some('.') => {
+                        self.advance();
+                        self.advance();
+                        Token::Operator("..".to_string())
+                    },
                     '(' | ')' | '{' | '}' | '[' | ']' | ':' | ';' | ',' | '.' => {
                         self.advance();
                         Token::Separator(c.to_string())
                     },
+                    '"' if self.input[self.position..].starts_with("\"\"") => self.tokenize_raw_string_literal(),
                     '"' => self.tokenize_string_literal(),
                     _ => {
                         self.advance();
-                        continue;
+                        Token::Invalid(format!("Unexpected character {:?}", c))
                     }
                 };
                 return token;
             }
 
         }
-        Token::EOF
+        Token::Eof
     }
 
-    fn tokenize_integer(&mut self) -> Token {
+    /// Reads an integer, or a double if it has a decimal point or an
+    /// exponent. The `.` in `3.` or `1..2` isn't a decimal point, as no digit
+    /// comes straight after it.
+    fn tokenize_number(&mut self) -> Token {
         let mut num_str = String::new();
         
         while let Some(c) = self.current_char {
-            if c.is_digit(10) {
+            // Only treat the dot as a decimal point if a digit follows it
+            let is_decimal_point = c == '.' && !num_str.contains('.')
+                && self.peek().is_some_and(|next| next.is_ascii_digit());
+            if c.is_ascii_digit() || is_decimal_point {
                 num_str.push(c);
                 self.advance();
             }
@@ -97,15 +323,42 @@ impl<'a> Lexer<'a> {
             }
         }
 
-        let num: i64 = num_str.parse().unwrap_or(0);
-        Token::IntegerLiteral(num)
+        // An exponent, as in `1.5e3` or `2e-4`. A letter straight after the `e`
+        // means it's something else, like the start of a name
+        if matches!(self.current_char, Some('e' | 'E')) && !self.peek().is_some_and(|c| c.is_alphabetic() || c == '_') {
+            num_str.push('e');
+            self.advance(); // Skip "e"
+            if let Some(sign @ ('+' | '-')) = self.current_char {
+                num_str.push(sign);
+                self.advance();
+            }
+            let digits = num_str.len();
+            while let Some(c) = self.current_char.filter(char::is_ascii_digit) {
+                num_str.push(c);
+                self.advance();
+            }
+            if num_str.len() == digits {
+                return Token::Invalid(format!("{} isn't a number - the exponent needs digits after it", num_str));
+            }
+        }
+
+        if num_str.contains(['.', 'e']) {
+            let num: f64 = num_str.parse().unwrap_or(0.0);
+            return Token::DoubleLiteral(num);
+        }
+        match num_str.parse() {
+            Ok(num) => Token::IntegerLiteral(num),
+            Err(_) => Token::Invalid(format!("{} is too big for an integer (the biggest is {})", num_str, i64::MAX)),
+        }
     }
 
     fn tokenize_identifier_or_keyword(&mut self) -> Token {
         let mut identifier = String::new();
         
         while let Some(c) = self.current_char {
-            if c.is_alphanumeric() || c == '_' {
+            // A name from a namespaced import, e.g. `math.pi`, is one identifier
+            let is_member_dot = c == '.' && self.peek().is_some_and(char::is_alphabetic);
+            if c.is_alphanumeric() || c == '_' || is_member_dot {
                 identifier.push(c);
                 self.advance();
             }
@@ -114,36 +367,220 @@ impl<'a> Lexer<'a> {
             }
         }
         
-        match identifier.as_str() {
-            "with" | "set" | "as" | "to" | "then" | "do" | "print" | "where" | "loop" | "iter" => {
-                Token::Keyword(identifier)
-            },
-            _ => Token::Identifier(identifier),
+        match Keyword::from_word(&identifier) {
+            Some(keyword) => Token::Keyword(keyword),
+            None => Token::Identifier(identifier),
         }
     }
 
+    /// Reads a string written between quotes, where `\n`, `\t`, `\\` and `\"`
+    /// stand for a newline, a tab, a backslash and a quote. Any other
+    /// backslash is kept as it is, so `"C:\Users"` still means what it says.
     fn tokenize_string_literal(&mut self) -> Token {
         let mut string_literal = String::new();
-        self.advance();
+        self.advance(); // Skip the opening quote
+        while let Some(c) = self.current_char {
+            self.advance();
+            match c {
+                '"' => return Token::StringLiteral(string_literal),
+                '\\' => {
+                    let escaped = match self.current_char {
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some('\\') => '\\',
+                        Some('"') => '"',
+                        _ => {
+                            string_literal.push('\\');
+                            continue;
+                        }
+                    };
+                    string_literal.push(escaped);
+                    self.advance();
+                }
+                _ => string_literal.push(c),
+            }
+        }
+        Token::Invalid("This string has no closing `\"`".to_string())
+    }
+
+    /// Reads a string written between triple quotes, which can hold quotes
+    /// and newlines as they are. Quotes just before the closing `"""` are
+    /// part of the string, so it can end in one.
+    fn tokenize_raw_string_literal(&mut self) -> Token {
+        let mut string_literal = String::new();
+        for _ in 0..3 {
+            self.advance(); // Skip the opening quotes
+        }
         while let Some(c) = self.current_char {
             if c == '"' {
-                self.advance();
-                break;
+                let mut quotes = 0;
+                while self.current_char == Some('"') {
+                    quotes += 1;
+                    self.advance();
+                }
+                if quotes >= 3 {
+                    string_literal.push_str(&"\"".repeat(quotes - 3));
+                    return Token::StringLiteral(string_literal);
+                }
+                string_literal.push_str(&"\"".repeat(quotes));
             }
             else {
                 string_literal.push(c);
                 self.advance();
             }
         }
-        Token::StringLiteral(string_literal)
+        Token::Invalid("This string has no closing `\"\"\"`".to_string())
+    }
+}
+
+/// Lets a lexer be used as an iterator over its tokens, e.g.
+/// `lexer.collect::<Vec<_>>()`. The iterator ends at the end of the input,
+/// without yielding `Token::Eof`.
+impl Iterator for Lexer<'_> {
+    type Item = Token;
+
+    fn next(&mut self) -> Option<Token> {
+        match self.get_next_token() {
+            Token::Eof => None,
+            token => Some(token),
+        }
     }
 }
 
+#[allow(dead_code)] // Debugging helper
 pub fn test(input: &str) {
-    let mut lexer = Lexer::new(input);
-    let mut token = lexer.get_next_token();
-    while token != Token::EOF {
+    for token in Lexer::new(input) {
         println!("{:?}", token);
-        token = lexer.get_next_token();
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(source: &str) -> Vec<Token> {
+        Lexer::new(source).collect()
+    }
+
+    #[test]
+    fn exponents_make_doubles() {
+        assert_eq!(tokens("1.5e3"), vec![Token::DoubleLiteral(1500.0)]);
+        assert_eq!(tokens("2e-4"), vec![Token::DoubleLiteral(0.0002)]);
+        assert_eq!(tokens("1E+2"), vec![Token::DoubleLiteral(100.0)]);
+        assert_eq!(tokens("1e"), vec![Token::Invalid("1e isn't a number - the exponent needs digits after it".to_string())]);
+        assert_eq!(tokens("1e+"), vec![Token::Invalid("1e+ isn't a number - the exponent needs digits after it".to_string())]);
+    }
+
+    #[test]
+    #[allow(clippy::approx_constant)] // It's 3.14 as written, not pi
+    fn a_dot_is_only_a_decimal_point_before_a_digit() {
+        assert_eq!(tokens("3.14"), vec![Token::DoubleLiteral(3.14)]);
+        assert_eq!(
+            tokens("1..2"),
+            vec![Token::IntegerLiteral(1), Token::Operator("..".to_string()), Token::IntegerLiteral(2)]
+        );
+        assert_eq!(tokens("3."), vec![Token::IntegerLiteral(3), Token::Separator(".".to_string())]);
+    }
+
+    #[test]
+    fn comments_are_skipped_to_the_end_of_the_line() {
+        assert_eq!(
+            tokens("print 1 # hi\nprint 2"),
+            vec![
+                Token::Keyword(Keyword::Print),
+                Token::IntegerLiteral(1),
+                Token::Keyword(Keyword::Print),
+                Token::IntegerLiteral(2),
+            ]
+        );
+    }
+
+    #[test]
+    fn escapes_in_strings_are_decoded() {
+        // Three characters, with a tab in the middle
+        assert_eq!(tokens(r#""a\tb""#), vec![Token::StringLiteral(['a', '\t', 'b'].iter().collect())]);
+        assert_eq!(tokens(r#""\"\\\n\q""#), vec![Token::StringLiteral("\"\\\n\\q".to_string())]);
+    }
+
+    #[test]
+    fn unterminated_strings_are_errors() {
+        assert_eq!(tokens("\"abc"), vec![Token::Invalid("This string has no closing `\"`".to_string())]);
+        assert_eq!(tokens(r#""abc\""#), vec![Token::Invalid("This string has no closing `\"`".to_string())]);
+    }
+
+    #[test]
+    fn dot_dot_is_one_operator() {
+        assert_eq!(
+            tokens(r#""a" .. "b""#),
+            vec![
+                Token::StringLiteral("a".to_string()),
+                Token::Operator("..".to_string()),
+                Token::StringLiteral("b".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn comparisons_are_one_operator_each() {
+        let operators = tokens("== != <= >= < > =");
+        let expected = ["==", "!=", "<=", ">=", "<", ">", "="].map(|operator| Token::Operator(operator.to_string()));
+        assert_eq!(operators, expected);
+    }
+
+    #[test]
+    fn comments_are_only_tokens_when_kept() {
+        let source = "print 1 # one\n# two";
+        assert_eq!(
+            Lexer::with_comments(source).collect::<Vec<_>>(),
+            vec![
+                Token::Keyword(Keyword::Print),
+                Token::IntegerLiteral(1),
+                Token::Comment(" one".to_string()),
+                Token::Comment(" two".to_string()),
+            ]
+        );
+        assert_eq!(tokens(source), vec![Token::Keyword(Keyword::Print), Token::IntegerLiteral(1)]);
+    }
+
+    /// A small xorshift generator, so the fuzz test is the same every run.
+    struct Random(u64);
+
+    impl Random {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+    }
+
+    /// Every token uses up at least one character, so the lexer has to reach
+    /// `Eof` within one token per character, and stay there.
+    fn assert_reaches_eof(source: &str) {
+        let mut lexer = Lexer::new(source);
+        let reached_eof = (0..=source.chars().count()).any(|_| lexer.get_next_token() == Token::Eof);
+        assert!(reached_eof, "never reached Eof for {:?}", source);
+        assert_eq!(lexer.get_next_token(), Token::Eof);
+    }
+
+    #[test]
+    fn any_input_lexes_to_eof() {
+        let pieces = [
+            "\"", "\"\"\"", "#", "\\", "\n", "\r\n", " ", ".", "..", "e", "E", "+", "-", "1", "0.5", "1e",
+            "99999999999999999999999", "1e999", "é", "日本", "🦀", "\u{FEFF}", "\u{0301}", "\u{FFFD}", "print", "x",
+        ];
+        let mut random = Random(0x2545_F491_4F6C_DD1D);
+        for _ in 0..2000 {
+            let length = random.below(40);
+            let from_pieces: String = (0..length).map(|_| pieces[random.below(pieces.len())]).collect();
+            assert_reaches_eof(&from_pieces);
+            // Raw bytes, with anything that isn't UTF-8 replaced, as a `&str` always holds UTF-8
+            let bytes: Vec<u8> = (0..length).map(|_| random.next() as u8).collect();
+            assert_reaches_eof(&String::from_utf8_lossy(&bytes));
+        }
+    }
+}
@@ -0,0 +1,12 @@
+// lib.rs - The Clarice programming language, for programs that embed it
+
+pub mod error;
+pub mod lexer;
+pub mod parser;
+pub mod symbol_table;
+pub mod type_checker;
+mod constant_folder;
+pub mod interpreter;
+pub mod formatter;
+mod json;
+mod binary;
@@ -1,45 +1,249 @@
-mod lexer;
-mod parser;
-mod symbol_table;
-mod type_checker;
-mod interpreter;
+use std::io::{IsTerminal, Read};
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::time::Instant;
 
 use linefeed::{Interface, ReadResult};
 
-use lexer::Lexer;
-use parser::Parser;
-use interpreter::Environment;
+use clarice::error::RuntimeError;
+use clarice::formatter;
+use clarice::lexer::Lexer;
+use clarice::parser::{ASTNode, Parser, STACK_SIZE};
+use clarice::interpreter::{EnvSnapshot, Environment, Value};
+use clarice::symbol_table::Type;
+use clarice::type_checker::TypeChecker;
 
-fn clarice_eval(input: String, environment: &mut Environment) -> String {
-    match input.as_str() {
-        "exit" => {
-            println!("Okay, shutting down the Clarice interactive mode.");
-            std::process::exit(0);
+/// The command-line options that change how programs are checked and run.
+#[derive(Clone, Copy)]
+struct Options {
+    typecheck: bool,
+    truthiness: bool,
+    yes_no: bool,
+    strict_coercion: bool,
+}
+
+impl Options {
+    /// A type checker set up for these options, or `None` with `--no-typecheck`.
+    fn type_checker(self) -> Option<TypeChecker> {
+        self.typecheck.then(|| {
+            let mut type_checker = TypeChecker::new();
+            type_checker.truthiness = self.truthiness;
+            type_checker.strict_coercion = self.strict_coercion;
+            type_checker
+        })
+    }
+
+    fn environment(self) -> Environment {
+        let mut environment = Environment::new();
+        environment.truthiness = self.truthiness;
+        environment.yes_no = self.yes_no;
+        environment.strict_coercion = self.strict_coercion;
+        environment
+    }
+}
+
+/// What `undo` needs to go back to before the last evaluation: the variables,
+/// and what the type checker knew about them.
+type UndoState = (EnvSnapshot, Option<TypeChecker>);
+
+const DEFAULT_PROMPT: &str = "Clarice> ";
+const CONTINUATION_PROMPT: &str = "     ... ";
+
+/// Everything the interactive mode keeps from one input to the next.
+struct Session {
+    options: Options, // What the session started with, for `:reset`
+    environment: Environment,
+    type_checker: Option<TypeChecker>,
+    undo: Option<UndoState>,
+    prompt: String,
+}
+
+impl Session {
+    fn new(options: Options) -> Self {
+        let mut environment = options.environment();
+        environment.max_iterations = Some(1_000_000);
+        environment.max_output_bytes = Some(64 * 1024);
+        Session {
+            options,
+            environment,
+            type_checker: options.type_checker(),
+            undo: None,
+            prompt: std::env::var("CLARICE_PROMPT").unwrap_or_else(|_| DEFAULT_PROMPT.to_string()),
+        }
+    }
+}
+
+fn clarice_eval(input: String, session: &mut Session) -> String {
+    if input == "exit" || input.starts_with("exit ") {
+        let code = input["exit".len()..].trim();
+        let code = if code.is_empty() { Ok(0) } else { code.parse::<i32>() };
+        match code {
+            Ok(code) => shut_down(code),
+            Err(_) => return "`exit` expects an integer status code".to_string(),
         }
+    }
+    if let Some(prompt) = input.strip_prefix(".prompt") {
+        // The quotes are optional, but without them trailing spaces get lost
+        let prompt = prompt.trim();
+        let prompt = prompt.strip_prefix('"').and_then(|p| p.strip_suffix('"')).unwrap_or(prompt);
+        session.prompt = if prompt.is_empty() { DEFAULT_PROMPT.to_string() } else { prompt.to_string() };
+        return "=> .prompt".to_string();
+    }
+    if let Some(expression) = input.strip_prefix(":type") {
+        return type_of(expression, session);
+    }
+    if let Some(code) = input.strip_prefix(":time") {
+        return time(code.trim(), session);
+    }
+    if let Some(path) = input.strip_prefix(":load") {
+        return load(path.trim(), session);
+    }
+    match input.as_str() {
         "help" => {
             println!("You can enter Clarice commands into the interactive prompt.");
-            println!("Type 'exit' to exit interactive mode.");
+            println!("Type 'undo' to undo the variable changes made by the last thing you entered.");
+            println!("Type ':reset' to start again with only the predefined variables, and the prompt put back.");
+            println!("Type ':load <file>' to run a file, keeping its functions and variables.");
+            println!("Type ':time <code>' to run some code and see how long it took.");
+            println!("Type ':type <expression>' to see the type of an expression without running it.");
+            println!("Type '.prompt \"text\"' to change the prompt, or '.prompt' on its own to put it back.");
+            println!("Type 'exit' to exit interactive mode, or 'exit <code>' to exit with a status code.");
             return "=> help".to_string();
-        } 
+        }
+        "undo" => {
+            return match session.undo.take() {
+                Some((snapshot, previous_checker)) => {
+                    session.environment.restore(snapshot);
+                    session.type_checker = previous_checker;
+                    "=> undo".to_string()
+                }
+                None => "There's nothing to undo".to_string(),
+            };
+        }
+        ":reset" => {
+            *session = Session::new(session.options);
+            return "=> :reset".to_string();
+        }
         "vars" => {
-            return format!("{:#?}", environment.variables);
+            return format!("{:#?}", session.environment.variables);
         }
         _ => (),
     }
-    let lexer = Lexer::new(&input);
+    match evaluate(&input, session) {
+        Ok(Some(value)) => format!("=> {}", session.environment.show(&value)),
+        Ok(None) => format!("=> {}", input),
+        Err(e) => e,
+    }
+}
+
+/// Parses, checks and runs some Clarice in the session, giving back the
+/// value of its last statement if it has one, or its errors, ready to print.
+fn evaluate(input: &str, session: &mut Session) -> Result<Option<Value>, String> {
+    let parsed_program = prepare(input, session)?;
+    session.environment.interpret(parsed_program).map_err(runtime_error)
+}
+
+/// Gives the message to print for an error from running something in the
+/// session, or leaves interactive mode if it was an `exit`.
+fn runtime_error(error: RuntimeError) -> String {
+    if let Some(code) = error.exit_code {
+        shut_down(code);
+    }
+    format!("Error: {}", error)
+}
+
+fn shut_down(code: i32) -> ! {
+    println!("Okay, shutting down the Clarice interactive mode.");
+    std::process::exit(code);
+}
+
+/// Runs some Clarice in the session for `:time`, showing how long it took
+/// to run after its output. Parsing and checking it isn't counted.
+fn time(input: &str, session: &mut Session) -> String {
+    let parsed_program = match prepare(input, session) {
+        Ok(program) => program,
+        Err(e) => return e,
+    };
+    let start = Instant::now();
+    let result = session.environment.interpret(parsed_program);
+    let elapsed = start.elapsed();
+    let output = match result {
+        Ok(Some(value)) => format!("=> {}", session.environment.show(&value)),
+        Ok(None) => format!("=> {}", input),
+        Err(e) => runtime_error(e),
+    };
+    format!("{}\nTook {:?}", output, elapsed)
+}
+
+/// Parses and checks some Clarice for the session, saving what `undo` needs
+/// to go back to before it runs.
+fn prepare(input: &str, session: &mut Session) -> Result<ASTNode, String> {
+    let lexer = Lexer::new(input);
     let mut parser = Parser::new(lexer);
 
-    let parsed_program = match parser.parse() {
+    let previous_checker = session.type_checker.clone();
+    let parsed_program = match &mut session.type_checker {
+        Some(type_checker) => parser.parse(type_checker),
+        None => parser.parse_only(),
+    };
+    let parsed_program = match parsed_program {
         Ok(program) => program,
-        Err(e) => {
-            return format!("Error during parsing: {}", e);
+        Err(errors) => {
+            // The checker still binds names whose values have errors, which only
+            // makes sense within one program - otherwise `set x to x + 1` would
+            // pass the second time it's entered
+            session.type_checker = previous_checker;
+            return Err(errors.iter()
+                .map(|e| format!("Error: {}", e))
+                .collect::<Vec<_>>()
+                .join("\n"));
         }
     };
 
-    environment.interpret(parsed_program);
+    session.undo = Some((session.environment.snapshot(), previous_checker));
+    Ok(parsed_program)
+}
+
+/// Runs a file in the session for `:load`, so that its functions and
+/// variables can be used from the prompt. Its imports are found relative to
+/// it, the same as when it's run on its own.
+fn load(path: &str, session: &mut Session) -> String {
+    let path = path.strip_prefix('"').and_then(|p| p.strip_suffix('"')).unwrap_or(path);
+    let input = match std::fs::read_to_string(path) {
+        Ok(input) => input,
+        Err(e) => return format!("Couldn't read {}: {}", path, e),
+    };
+    let previous_imports = std::mem::replace(&mut session.environment.imports, imports_from(path));
+    if let Some(type_checker) = &mut session.type_checker {
+        type_checker.imports = imports_from(path);
+    }
+    let result = evaluate(&input, session);
+    session.environment.imports = previous_imports.clone();
+    if let Some(type_checker) = &mut session.type_checker {
+        type_checker.imports = previous_imports;
+    }
+    match result {
+        Ok(_) => format!("=> :load {}", path),
+        Err(e) => e,
+    }
+}
 
-    // This is where I'd put the return value. IF I HAD ONE
-    format!("=> {}", input)
+/// Prints the type the checker works out for an expression, for `:type`.
+/// Without type checking, the variables' types aren't known, so only
+/// expressions that don't use them can be given a type.
+fn type_of(input: &str, session: &Session) -> String {
+    let mut parser = Parser::new(Lexer::new(input));
+    let checked = parser.parse_single_expression().and_then(|expression| match &session.type_checker {
+        Some(type_checker) => type_checker.type_of(&expression),
+        None => TypeChecker::new().type_of(&expression),
+    });
+    match checked {
+        Ok(expression_type) => expression_type.to_string(),
+        Err(errors) => errors.iter()
+            .map(|e| format!("Error: {}", e))
+            .collect::<Vec<_>>()
+            .join("\n"),
+    }
 }
 
 fn clarice_welcome() {
@@ -49,34 +253,231 @@ fn clarice_welcome() {
     println!("Type `help` for help or `exit` to leave interactive mode.");
 }
 
-fn interactive() {
+fn interactive(options: Options) {
     // Welcome
     clarice_welcome();
 
     // Linefeed
     let interface = Interface::new("Clarice").unwrap();
-    let mut environment = Environment::new();
-    let prompt = String::from("Clarice> ");
-    interface.set_prompt(&prompt).unwrap();
+    let mut session = Session::new(options);
 
     loop {
         // Read
+        interface.set_prompt(&session.prompt).unwrap();
         while let ReadResult::Input(line) = interface.read_line().unwrap() {
-            let command = line.trim_end().to_string();
+            let mut command = line.trim_end().to_string();
+            // A line ending in `\` carries on onto the next one
+            if command.ends_with('\\') {
+                interface.set_prompt(CONTINUATION_PROMPT).unwrap();
+                while command.ends_with('\\') {
+                    match interface.read_line().unwrap() {
+                        ReadResult::Input(line) => {
+                            command.push('\n');
+                            command.push_str(line.trim_end());
+                        }
+                        _ => break,
+                    }
+                }
+            }
             interface.add_history_unique(command.clone());
 
             // Eval
-            let eval_result = clarice_eval(command, &mut environment);
+            let eval_result = clarice_eval(command, &mut session);
             
             // Print
             println!("{}", eval_result);
+
+            // The prompt might have been changed with `.prompt`
+            interface.set_prompt(&session.prompt).unwrap();
         }
         // Loop
         // ...well, it will loop by itself.
     }
 }
 
+/// Reads and parses a Clarice file, type checking it too unless that's turned
+/// off. Any errors are printed, and then Clarice exits.
+fn parse_file(path: &str, options: Options) -> ASTNode {
+    let input = match std::fs::read_to_string(path) {
+        Ok(input) => input,
+        Err(e) => {
+            eprintln!("Couldn't read {}: {}", path, e);
+            std::process::exit(1);
+        }
+    };
+    parse_source(&input, imports_from(path), options)
+}
+
+/// The starting point for finding the files a file imports.
+fn imports_from(path: &str) -> Vec<PathBuf> {
+    std::fs::canonicalize(path).into_iter().collect()
+}
+
+/// Parses (and usually type checks) a program, printing any errors and
+/// exiting if there are some. `imports` is the file it came from, if any.
+fn parse_source(input: &str, imports: Vec<PathBuf>, options: Options) -> ASTNode {
+    let lexer = Lexer::new(input);
+    let mut parser = Parser::new(lexer);
+
+    let parsed_program = match options.type_checker() {
+        Some(mut type_checker) => {
+            type_checker.imports = imports;
+            type_checker.declare("args".to_string(), Type::List(Box::new(Type::String)));
+            parser.parse(&mut type_checker)
+        }
+        None => parser.parse_only(),
+    };
+    match parsed_program {
+        Ok(program) => program,
+        Err(errors) => {
+            for e in errors {
+                eprintln!("Error: {}", e);
+            }
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Runs a file, with `args` set to the arguments after its path.
+fn run_file(path: &str, args: &[&str], options: Options) {
+    run_program(parse_file(path, options), imports_from(path), args, options);
+}
+
+/// Runs a program piped into Clarice, e.g. `echo 'print 1 + 2' | clarice`.
+fn run_stdin(options: Options) {
+    let mut input = String::new();
+    if let Err(e) = std::io::stdin().read_to_string(&mut input) {
+        eprintln!("Couldn't read standard input: {}", e);
+        std::process::exit(1);
+    }
+    run_program(parse_source(&input, Vec::new(), options), Vec::new(), &[], options);
+}
+
+fn run_program(parsed_program: ASTNode, imports: Vec<PathBuf>, args: &[&str], options: Options) {
+    let mut environment = options.environment();
+    environment.imports = imports;
+    let args = args.iter().map(|arg| Value::String((*arg).into())).collect();
+    environment.set("args".to_string(), Value::List(Rc::new(args)));
+    if let Err(e) = environment.interpret(parsed_program) {
+        if let Some(code) = e.exit_code {
+            std::process::exit(code);
+        }
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+/// Checks a Clarice file for syntax and type errors without running it.
+fn check_file(path: &str, options: Options) {
+    parse_file(path, Options { typecheck: true, ..options });
+    println!("No errors found in {}", path);
+}
+
+/// Reformats a Clarice file, printing the result or, with `--write`,
+/// rewriting the file in place.
+fn format_file(path: &str, write: bool) {
+    let input = match std::fs::read_to_string(path) {
+        Ok(input) => input,
+        Err(e) => {
+            eprintln!("Couldn't read {}: {}", path, e);
+            std::process::exit(1);
+        }
+    };
+    let formatted = match formatter::format_source(&input) {
+        Ok(formatted) => formatted,
+        Err(errors) => {
+            for e in errors {
+                eprintln!("Error: {}", e);
+            }
+            std::process::exit(1);
+        }
+    };
+    if !write {
+        print!("{}", formatted);
+    }
+    else if let Err(e) = std::fs::write(path, formatted) {
+        eprintln!("Couldn't write {}: {}", path, e);
+        std::process::exit(1);
+    }
+}
+
+const USAGE: &str = "\
+Usage:
+    clarice                        Start the interactive mode, or run a program piped in
+    clarice repl                   Start the interactive mode
+    clarice <file> [args...]       Run a file, which can read the arguments after it as `args`
+    clarice run <file> [args...]   Run a file
+    clarice check <file>           Check a file for errors without running it
+    clarice fmt [--write] <file>   Print a file in the standard layout, or rewrite it with --write
+
+Options:
+    --no-typecheck   Skip type checking
+    --truthiness     Let any value be a condition, not only booleans
+    --yes-no         Print booleans as yes and no rather than true and false
+    --strict-coercion
+                     Only let `..` join strings, so numbers need `str` first
+    --               Pass the arguments after this to the program, even ones starting with -";
+
+fn usage_error(message: &str) -> ! {
+    eprintln!("{}\n\n{}", message, USAGE);
+    std::process::exit(1);
+}
+
 fn main() {
-    interactive();
+    // Parsing, checking and running a program recurse through its nesting,
+    // which can go deeper than the main thread's stack allows
+    let command_line = std::thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(run_command_line)
+        .expect("Couldn't start a thread to run Clarice on");
+    if let Err(panic) = command_line.join() {
+        std::panic::resume_unwind(panic);
+    }
 }
 
+/// Does what the command line asks for.
+fn run_command_line() {
+    let mut options = Options { typecheck: true, truthiness: false, yes_no: false, strict_coercion: false };
+    let mut write = false;
+    let mut arguments = Vec::new();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            // Everything after `--` is for the program, even if it looks like an option
+            "--" => arguments.extend(args.by_ref()),
+            "--no-typecheck" => options.typecheck = false,
+            "--truthiness" => options.truthiness = true,
+            "--yes-no" => options.yes_no = true,
+            "--strict-coercion" => options.strict_coercion = true,
+            "--write" => write = true,
+            "--help" | "-h" => {
+                println!("{}", USAGE);
+                return;
+            }
+            option if option.starts_with('-') => usage_error(&format!("Unknown option `{}`", option)),
+            _ => arguments.push(arg),
+        }
+    }
+
+    if !options.typecheck {
+        eprintln!("Warning: type checking is turned off, so type errors will only show up as the program runs.");
+    }
+
+    let arguments: Vec<&str> = arguments.iter().map(String::as_str).collect();
+    match arguments.as_slice() {
+        // A program piped in is run, rather than being typed into the interactive mode
+        [] if !std::io::stdin().is_terminal() => run_stdin(options),
+        [] | ["repl"] => interactive(options),
+        ["run", path, args @ ..] => run_file(path, args, options),
+        ["check", path] => check_file(path, options),
+        ["fmt", path] => format_file(path, write),
+        [command @ ("repl" | "run" | "check" | "fmt"), ..] => {
+            usage_error(&format!("Wrong number of arguments for `{}`", command))
+        }
+        // Anything else is a file to run, unless it doesn't look like one
+        [path, args @ ..] if std::path::Path::new(path).exists() || path.contains(['.', '/']) => {
+            run_file(path, args, options)
+        }
+        [command, ..] => usage_error(&format!("Unknown command `{}`", command)),
+    }
+}
@@ -1,19 +1,31 @@
 // parser.rs - Parser for the Clarice programming language
 
-use crate::lexer::{Token, Lexer};
-use crate::symbol_table::{SymbolTable, Type};
-use crate::type_checker::{self, TypeChecker};
+use crate::lexer::{Keyword, Token, Lexer};
+use crate::error::ClariceError;
+use crate::symbol_table::Type;
+use crate::type_checker::TypeChecker;
+use crate::constant_folder::fold_constants;
+use std::path::{Path, PathBuf};
 use std::vec::Vec;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum ASTNode {
     Program(Vec<Statement>),
 }
 
-#[derive(Debug, Clone)]
-pub enum Statement {
+/// A statement and the line it starts on, so that errors found in it can
+/// say where it is.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Statement {
+    pub kind: StatementKind,
+    pub line: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StatementKind {
     With(Box<WithStatement>),
     Set(Box<SetStatement>),
+    Destructure(Box<DestructureStatement>),
     As(Box<AsStatement>),
     To(Box<ToStatement>),
     Then(Box<ThenStatement>),
@@ -21,70 +33,128 @@ pub enum Statement {
     Print(Box<PrintStatement>),
     Where(Box<WhereStatement>),
     Loop(Box<LoopStatement>),
+    While(Box<WhileStatement>),
     Iter(Box<IterStatement>),
+    Exit(Box<ExitStatement>),
+    Match(Box<MatchStatement>),
+    Function(Box<FunctionStatement>),
+    Break(Option<String>), // With the label of the loop to leave, if it isn't the innermost
+    Next, // Skips to the next iteration of the innermost loop
+    Pass, // Does nothing, for a block that has nothing to do yet
+    // A comment, only kept when parsing for the formatter: the text after the
+    // `#`, and whether it was on the same line as the code before it
+    Comment(String, bool),
+    Import(Box<ImportStatement>),
     Expression(Box<Expression>),
+    // Stands in for a statement that couldn't be parsed. A program with
+    // errors is never folded, checked or run, so nothing past the parser sees it
+    Error,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct WithStatement {
     pub identifier: String,
     pub expression: Box<Expression>,
+    pub body: Option<Box<Statement>>, // The statement that can see the variable, if there is one
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct SetStatement {
     pub variable: String,
+    pub indexes: Vec<Expression>, // For setting an element, e.g. the `[0]` of `set xs[0] to 1`
+    pub expression: Box<Expression>,
+}
+
+/// `set [a, b] to list`, which sets each variable to the matching element.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DestructureStatement {
+    pub variables: Vec<String>,
     pub expression: Box<Expression>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct AsStatement {
     pub identifier: String,
     pub expression: Box<Expression>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct ToStatement {
     pub identifier: String,
     pub expression: Box<Expression>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct ThenStatement {
     pub statement: Box<Statement>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct DoStatement {
     pub expression: Box<Expression>,
     pub statement: Box<Statement>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct PrintStatement {
     pub expression: Box<Expression>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct WhereStatement {
-    pub condition: Box<Expression>,
-    pub true_branch: ASTNode,
-    pub false_branch: Option<ASTNode>,
+    pub conditions: Vec<Expression>, // All of them have to hold, e.g. `where a, b do`
+    pub true_branch: Vec<Statement>,
+    pub false_branch: Option<Vec<Statement>>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct LoopStatement {
-    pub expression: Box<Expression>,
+    pub label: Option<String>, // The `outer` in `loop as outer do`, for `break outer`
+    pub body: Vec<Statement>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WhileStatement {
+    pub condition: Box<Expression>,
+    pub body: Vec<Statement>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct IterStatement {
     pub variable: String,
+    pub value_variable: Option<String>, // The `v` in `iter k, v in map`
     pub iterable: Box<Expression>,
-    pub expression: Box<Expression>,
+    pub body: Vec<Statement>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExitStatement {
+    pub code: Option<Box<Expression>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImportStatement {
+    pub path: String,
+    pub alias: Option<String>, // With `as`, the file's definitions are named e.g. `math.pi`
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MatchStatement {
+    pub scrutinee: Box<Expression>,
+    pub cases: Vec<(Expression, Vec<Statement>)>,
+    pub default: Option<Vec<Statement>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionStatement {
+    pub name: String,
+    pub parameters: Vec<String>,
+    pub parameter_types: Vec<Option<Type>>, // One for each parameter, if it was annotated
+    pub return_type: Option<Type>,
+    pub body: Vec<Statement>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Expression {
     Identifier(String),
     IntegerLiteral(i64),
@@ -92,182 +162,418 @@ pub enum Expression {
     StringLiteral(String),
     BooleanLiteral(bool),
     ListLiteral(Vec<Expression>),
+    MapLiteral(Vec<(Expression, Expression)>), // `{"a": 1, "b": 2}`
     FunctionCall(String, Vec<Expression>),
+    BinaryOperation(Box<Expression>, String, Box<Expression>),
+    Block(Vec<Statement>), // A `do` block used as a value
+    Lambda(Box<FunctionStatement>), // A function without a name, e.g. `fn(x) do x * 2 end`
+    Conditional(Box<Expression>, Box<Expression>, Box<Expression>), // `if condition then a else b`
+    Cast(Box<Expression>, Type), // `x as integer`
+    Index(Box<Expression>, Box<Expression>), // `xs[i]`
+    Comprehension(Box<Comprehension>), // `[x * 2 for x in xs where x in ys]`
+    Error, // Stands in for an expression that couldn't be parsed, like `StatementKind::Error`
+}
+
+impl Expression {
+    /// Takes an operation apart into its left operand, operator and right
+    /// operand, leaving placeholders behind, or gives back `None` if this
+    /// isn't an operation.
+    pub fn take_operation(&mut self) -> Option<(Expression, String, Expression)> {
+        let Expression::BinaryOperation(left, operator, right) = self else {
+            return None;
+        };
+        let placeholder = || Expression::BooleanLiteral(false);
+        Some((std::mem::replace(left, placeholder()), std::mem::take(operator), std::mem::replace(right, placeholder())))
+    }
+}
+
+impl Drop for Expression {
+    /// Takes a chain like `1 + 2 + 3` apart a link at a time, as dropping it
+    /// the usual way would recurse once per operator.
+    fn drop(&mut self) {
+        let mut operation = self.take_operation();
+        while let Some((mut left, _, _)) = operation {
+            operation = left.take_operation();
+        }
+    }
 }
 
+/// A list built from another collection, e.g. `[x * 2 for x in xs]`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Comprehension {
+    pub element: Expression,
+    pub variable: String,
+    pub iterable: Expression,
+    pub condition: Option<Expression>, // Only elements that meet it are kept
+}
+
+/// How deeply statements, expressions and types can nest inside each other,
+/// counting blocks, brackets, casts and indexes. Checking and running a
+/// program recurse through its nesting, so this keeps a deeply nested
+/// program from running out of stack. A chain of operators like `1 + 2 + 3`
+/// doesn't nest, as it's checked and run a link at a time.
+pub const MAX_NESTING: usize = 5_000;
+
+/// How much stack programs are parsed, checked and run with, which is
+/// enough for `MAX_NESTING` levels of any kind of nesting.
+pub const STACK_SIZE: usize = 256 * 1024 * 1024;
+
+/// The operators that compare two values, giving back a boolean.
+pub const COMPARISONS: [&str; 6] = ["==", "!=", "<", ">", "<=", ">="];
+
 pub struct Parser<'a> {
     lexer: Lexer<'a>,
     current_token: Token,
+    line: usize,
+    starts_line: bool, // Whether the current token is the first on its line
+    after_space: bool, // Whether there's space between the current token and the one before it
+    errors: Vec<ClariceError>,
+    depth: usize, // How many levels of nesting the current token is inside
+    comments: Vec<Statement>, // Comments passed since the last statement, if the lexer keeps them
+    too_deep: bool, // Whether the nesting went past `MAX_NESTING`, after which the rest is skipped
 }
 
 impl<'a> Parser<'a> {
     pub fn new(lexer: Lexer<'a>) -> Self {
         let mut parser = Parser {
             lexer,
-            current_token: Token::EOF,
+            current_token: Token::Eof,
+            line: 1,
+            starts_line: true,
+            after_space: false,
+            errors: Vec::new(),
+            depth: 0,
+            too_deep: false,
+            comments: Vec::new(),
         };
         parser.advance();
         parser
     }
 
     fn advance(&mut self) {
-        self.current_token = self.lexer.get_next_token();
+        let mut token = self.lexer.get_next_token();
+        loop {
+            match token {
+                // The lexer can't report errors itself, so it hands them over as tokens
+                Token::Invalid(message) => self.errors.push(ClariceError::at_line(message, self.lexer.token_line)),
+                // Comments are kept aside until the next statement, so they don't get in the way
+                Token::Comment(text) => self.comments.push(Statement {
+                    kind: StatementKind::Comment(text, !self.lexer.token_starts_line),
+                    line: self.lexer.token_line,
+                }),
+                _ => break,
+            }
+            token = self.lexer.get_next_token();
+        }
+        self.current_token = token;
+        self.line = self.lexer.token_line;
+        self.starts_line = self.lexer.token_starts_line;
+        self.after_space = self.lexer.token_after_space;
+    }
+
+    fn error(&mut self, message: String) {
+        // Past too much nesting, every block left open would be another error
+        if !self.too_deep {
+            self.errors.push(ClariceError::at_line(message, self.line));
+        }
+    }
+
+    /// Goes one level deeper into nested statements, expressions or types,
+    /// giving back whether it could. Past `MAX_NESTING`, it reports an error
+    /// and skips the rest of the program, as there's no telling where all
+    /// the nesting ends.
+    fn nest(&mut self) -> bool {
+        if self.depth < MAX_NESTING {
+            self.depth += 1;
+            return true;
+        }
+        self.error(format!(
+            "This is nested more than {} levels deep - blocks, brackets, casts and indexes all count",
+            MAX_NESTING
+        ));
+        self.too_deep = true;
+        while self.current_token != Token::Eof {
+            self.advance();
+        }
+        false
+    }
+
+    /// Recovers from a syntax error by skipping tokens up to the start of the
+    /// next statement: a statement keyword, the end of a block, a `;`, or the
+    /// start of a new line. This stops one mistake from turning the rest of
+    /// the program into errors.
+    fn synchronize(&mut self) {
+        loop {
+            match &self.current_token {
+                Token::Eof => return,
+                Token::Keyword(
+                    Keyword::With | Keyword::Set | Keyword::Then | Keyword::Print | Keyword::Where | Keyword::Otherwise
+                        | Keyword::End | Keyword::Loop | Keyword::While | Keyword::Iter | Keyword::Exit | Keyword::Match
+                        | Keyword::Case | Keyword::Fn | Keyword::Break | Keyword::Next | Keyword::Pass | Keyword::Import
+                ) => return,
+                Token::Separator(separator) if separator == ";" => {
+                    self.advance(); // Skip ";"
+                    return;
+                }
+                _ if self.starts_line => return,
+                _ => self.advance(),
+            }
+        }
     }
 
     fn parse_program(&mut self) -> ASTNode {
         let mut statements = Vec::new();
-        while self.current_token != Token::EOF {
+        while self.current_token != Token::Eof {
+            if self.skip_separator() {
+                continue;
+            }
+            statements.append(&mut self.comments);
             let statement = self.parse_statement();
             statements.push(statement);
         }
+        statements.append(&mut self.comments);
         ASTNode::Program(statements)
     }
 
+    /// Skips a `;`, which can separate statements on the same line.
+    fn skip_separator(&mut self) -> bool {
+        if self.current_token == Token::Separator(";".to_string()) {
+            self.advance(); // Skip ";"
+            return true;
+        }
+        false
+    }
+
     fn parse_statement(&mut self) -> Statement {
+        let line = self.line;
+        if !self.nest() {
+            return Statement { kind: StatementKind::Error, line };
+        }
+        let errors = self.errors.len();
+        let statement = Statement { kind: self.parse_statement_kind(), line };
+        if self.errors.len() == errors {
+            self.reject_trailing_keyword("the statement");
+        }
+        if self.errors.len() > errors {
+            self.synchronize();
+        }
+        self.depth -= 1;
+        statement
+    }
+
+    /// Reports a keyword left over on the same line once a statement is
+    /// complete, which can only be a mistake, e.g. the second `to` of
+    /// `set x to 1 to 2`. It would otherwise start a statement of its own.
+    /// Gives back whether there was one.
+    fn reject_trailing_keyword(&mut self, after: &str) -> bool {
+        match self.current_token {
+            Token::Keyword(keyword @ (Keyword::To | Keyword::As | Keyword::In | Keyword::Else)) if !self.starts_line => {
+                self.error(format!("Unexpected `{}` after {}", keyword, after));
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn parse_statement_kind(&mut self) -> StatementKind {
+        // An expression on its own is a statement too, such as a function call, or
+        // the last statement of a function body, whose value it gives back
+        if self.at_atom() || self.current_token == Token::Keyword(Keyword::If) || self.at_lambda() {
+            return StatementKind::Expression(Box::new(self.parse_expression()));
+        }
         match &self.current_token {
-            Token::Keyword(ref keyword) => {
-                match keyword.as_str() {
-                    "with" => Statement::With(self.parse_with_statement()),
-                    "set" => Statement::Set(self.parse_set_statement()),
-                    "as" => Statement::As(self.parse_as_statement()),
-                    "to" => Statement::To(self.parse_to_statement()),
-                    "then" => Statement::Then(self.parse_then_statement()),
-                    "do" => Statement::Do(self.parse_do_statement()),
-                    "print" => Statement::Print(self.parse_print_statement()),
-                    "where" => Statement::Where(self.parse_where_statement()),
-                    "loop" => Statement::Loop(self.parse_loop_statement()),
-                    "iter" => Statement::Iter(self.parse_iter_statement()),
-                    _ => {
-                        println!("Clarice doesn't recognize the keyword \"{}\".", keyword);
+            Token::Keyword(keyword) => {
+                match keyword {
+                    Keyword::With => self.parse_with_statement(),
+                    Keyword::Set => self.parse_set_statement(),
+                    Keyword::As => self.parse_as_statement(),
+                    Keyword::To => self.parse_to_statement(),
+                    Keyword::Then => StatementKind::Then(self.parse_then_statement()),
+                    Keyword::Do => StatementKind::Do(self.parse_do_statement()),
+                    Keyword::Print => StatementKind::Print(self.parse_print_statement()),
+                    Keyword::Where => StatementKind::Where(self.parse_where_statement()),
+                    Keyword::Loop => StatementKind::Loop(self.parse_loop_statement()),
+                    Keyword::While => StatementKind::While(self.parse_while_statement()),
+                    Keyword::Iter => StatementKind::Iter(self.parse_iter_statement()),
+                    Keyword::Exit => StatementKind::Exit(self.parse_exit_statement()),
+                    Keyword::Match => StatementKind::Match(self.parse_match_statement()),
+                    Keyword::Fn => StatementKind::Function(self.parse_function_statement()),
+                    Keyword::Break => {
+                        self.advance(); // Skip "break"
+                        match self.current_token {
+                            Token::Identifier(ref label) if !self.starts_line => {
+                                let label = label.clone();
+                                self.advance();
+                                StatementKind::Break(Some(label))
+                            }
+                            _ => StatementKind::Break(None),
+                        }
+                    }
+                    Keyword::Next => {
+                        self.advance(); // Skip "next"
+                        StatementKind::Next
+                    }
+                    Keyword::Pass => {
+                        self.advance(); // Skip "pass"
+                        StatementKind::Pass
+                    }
+                    Keyword::Import => StatementKind::Import(self.parse_import_statement()),
+                    Keyword::End | Keyword::Otherwise | Keyword::In | Keyword::Case | Keyword::If | Keyword::Else | Keyword::For | Keyword::Or => {
+                        self.error(format!("`{}` can't start a statement", keyword));
                         self.advance();
-                        // Placeholder error handling
-                        Statement::Print(Box::new(PrintStatement {
-                            expression: Box::new(Expression::StringLiteral("Unknown Keyword.".to_string())),
-                        }))
+                        StatementKind::Error
                     }
                 }
             },
-            Token::Identifier(_) => {
-                // Handle identifiers in specific contexts, if needed
-                println!("Expected a statement, got {:?}", self.current_token);
-                self.advance();
-                Statement::Print(Box::new(PrintStatement {
-                    expression: Box::new(Expression::StringLiteral("Unexpected Identifier.".to_string())),
-                }))
-            },
             _ => {
-                println!("Expected a statement, got {:?}", self.current_token);
+                self.error(format!("Expected a statement, got {:?}", self.current_token));
                 self.advance();
-                Statement::Print(Box::new(PrintStatement {
-                    expression: Box::new(Expression::StringLiteral("No Statement.".to_string())),
-                }))
+                StatementKind::Error
             }
         }
     }
 
-    fn parse_with_statement(&mut self) -> Box<WithStatement> {
+    fn parse_with_statement(&mut self) -> StatementKind {
         self.advance(); // Skip "with"
         let identifier = match self.current_token {
             Token::Identifier(ref id) => id.clone(),
             _ => {
-                println!("Expected identifier after 'with' keyword, got {:?}", self.current_token);
-                return Box::new(WithStatement {
-                    identifier: "error".to_string(),
-                    expression: Box::new(Expression::StringLiteral("No Expression (With)".to_string())),
-                });
+                self.error(format!("Expected identifier after 'with' keyword, got {:?}", self.current_token));
+                return StatementKind::Error;
             }
         };
         self.advance(); // Advance to next token
-        if self.current_token != Token::Keyword("as".to_string()) {
-            println!("Expected 'as' after identifier, got {:?}", self.current_token);
+        if self.current_token != Token::Keyword(Keyword::As) {
+            let mut message = format!("Expected 'as' after identifier, got {:?}", self.current_token);
             if self.current_token == Token::Operator("=".to_string()) {
-                println!("`=` is not used for variable assignment in Clarice. Use `as` instead.");
+                message.push_str(" - `=` is not used for variable assignment in Clarice. Use `as` instead.");
             }
-            return Box::new(WithStatement {
-                identifier: "error".to_string(),
-                expression: Box::new(Expression::StringLiteral("Invalid Keyword.".to_string())),
-            });
+            self.error(message);
+            return StatementKind::Error;
         }
         self.advance(); // Skip "as"
         let expression = self.parse_expression();
-        Box::new(WithStatement {
+        if self.reject_trailing_keyword("the assignment") {
+            return StatementKind::Error;
+        }
+        while self.skip_separator() {}
+        // The variable only lasts for the statement after it, if the block hasn't ended
+        let body = match self.current_token {
+            Token::Eof | Token::Keyword(Keyword::End | Keyword::Otherwise | Keyword::Case) => None,
+            _ => Some(Box::new(self.parse_statement())),
+        };
+        StatementKind::With(Box::new(WithStatement {
             identifier,
             expression: Box::new(expression),
-        })
+            body,
+        }))
     }
 
-    fn parse_set_statement(&mut self) -> Box<SetStatement> {
+    fn parse_set_statement(&mut self) -> StatementKind {
         self.advance(); // Skip "set"   
         let identifier = match self.current_token {
             Token::Identifier(ref id) => id.clone(),
+            Token::Separator(ref s) if s == "[" => return self.parse_destructure_statement(),
             _ => {
-                println!("Expected identifier after 'set' keyword, got {:?}", self.current_token);
-                return Box::new(SetStatement {
-                    variable: "error".to_string(),
-                    expression: Box::new(Expression::StringLiteral("No Expression (Set)".to_string())),
-                });
+                self.error(format!("Expected identifier after 'set' keyword, got {:?}", self.current_token));
+                return StatementKind::Error;
             }
         };
         self.advance(); // Advance to next token
-        if self.current_token != Token::Keyword("to".to_string()) {
-            println!("Expected 'to' after identifier, got {:?}", self.current_token);
+        let mut indexes = Vec::new();
+        while self.at_index() {
+            self.advance(); // Skip "["
+            indexes.push(self.parse_expression());
+            if self.current_token != Token::Separator("]".to_string()) {
+                self.error(format!("Expected ']' after the index, got {:?}", self.current_token));
+                break;
+            }
+            self.advance(); // Skip "]"
+        }
+        if self.current_token != Token::Keyword(Keyword::To) {
+            let mut message = format!("Expected 'to' after identifier, got {:?}", self.current_token);
             if self.current_token == Token::Operator("=".to_string()) {
-                println!("`=` is not used for variable assignment in Clarice. Use `to` instead.");
+                message.push_str(" - `=` is not used for variable assignment in Clarice. Use `to` instead.");
             }
-            else if self.current_token == Token::Keyword("as".to_string()) {
-                println!("`as` is not used for the `set` statement. Use `to` instead.");
+            else if self.current_token == Token::Keyword(Keyword::As) {
+                message.push_str(" - `as` is not used for the `set` statement. Use `to` instead.");
             }
-            return Box::new(SetStatement {
-                variable: "error".to_string(),
-                expression: Box::new(Expression::StringLiteral("Invalid Keyword.".to_string())),
-            });
+            self.error(message);
+            return StatementKind::Error;
         }
         self.advance(); // Skip "to"
         let expression = self.parse_expression();
-        Box::new(SetStatement {
+        self.reject_trailing_keyword("the assignment");
+        StatementKind::Set(Box::new(SetStatement {
             variable: identifier,
+            indexes,
             expression: Box::new(expression),
-        })
+        }))
+    }
+
+    /// Parses the rest of `set [a, b, c] to list`, after the `set`.
+    fn parse_destructure_statement(&mut self) -> StatementKind {
+        self.advance(); // Skip "["
+        let mut variables = Vec::new();
+        while self.current_token != Token::Separator("]".to_string()) {
+            match self.current_token {
+                Token::Identifier(ref id) => variables.push(id.clone()),
+                _ => {
+                    self.error(format!("Expected a variable name in `set [...]`, got {:?}", self.current_token));
+                    return StatementKind::Error;
+                }
+            }
+            self.advance(); // Advance past the variable name
+            if self.current_token == Token::Separator(",".to_string()) {
+                self.advance(); // Skip ","
+            }
+        }
+        self.advance(); // Skip "]"
+        if self.current_token != Token::Keyword(Keyword::To) {
+            self.error(format!("Expected 'to' after `set [...]`, got {:?}", self.current_token));
+            return StatementKind::Error;
+        }
+        self.advance(); // Skip "to"
+        let expression = self.parse_expression();
+        self.reject_trailing_keyword("the assignment");
+        StatementKind::Destructure(Box::new(DestructureStatement {
+            variables,
+            expression: Box::new(expression),
+        }))
     }
 
-    fn parse_as_statement(&mut self) -> Box<AsStatement> {
+    fn parse_as_statement(&mut self) -> StatementKind {
         self.advance(); // Skip "as"
         let identifier = match self.current_token {
             Token::Identifier(ref id) => id.clone(),
             _ => {
-                println!("Expected identifier after 'as' keyword, got {:?}", self.current_token);
-                return Box::new(AsStatement {
-                    identifier: "error".to_string(),
-                    expression: Box::new(Expression::StringLiteral("No Expression (As)".to_string())),
-                });
+                self.error(format!("Expected identifier after 'as' keyword, got {:?}", self.current_token));
+                return StatementKind::Error;
             }
         };
         self.advance(); // Advance to next token
         let expression = self.parse_expression();
-        Box::new(AsStatement {
+        StatementKind::As(Box::new(AsStatement {
             identifier,
             expression: Box::new(expression),
-        })
+        }))
     }
 
-    fn parse_to_statement(&mut self) -> Box<ToStatement> {
+    fn parse_to_statement(&mut self) -> StatementKind {
         self.advance(); // Skip "to"
         let identifier = match self.current_token {
             Token::Identifier(ref id) => id.clone(),
             _ => {
-                println!("Expected identifier after 'to' keyword, got {:?}", self.current_token);
-                return Box::new(ToStatement {
-                    identifier: "error".to_string(),
-                    expression: Box::new(Expression::StringLiteral("No Expression (To)".to_string())),
-                });
+                self.error(format!("Expected identifier after 'to' keyword, got {:?}", self.current_token));
+                return StatementKind::Error;
             }
         };
         self.advance(); // Advance to next token
         let expression = self.parse_expression();
-        Box::new(ToStatement {
+        StatementKind::To(Box::new(ToStatement {
             identifier,
             expression: Box::new(expression),
-        })
+        }))
     }
 
     fn parse_then_statement(&mut self) -> Box<ThenStatement> {
@@ -297,26 +603,60 @@ impl<'a> Parser<'a> {
 
     fn parse_where_statement(&mut self) -> Box<WhereStatement> {
         self.advance(); // Skip "where"
-        let condition = self.parse_expression();
-        let true_branch = self.parse_program();
-        let false_branch = if self.current_token == Token::Keyword("otherwise".to_string()) {
+        let mut conditions = vec![self.parse_expression()];
+        while self.current_token == Token::Separator(",".to_string()) {
+            self.advance(); // Skip ","
+            conditions.push(self.parse_expression());
+        }
+        let true_branch = self.parse_block();
+        let false_branch = if self.current_token == Token::Keyword(Keyword::Otherwise) {
             self.advance(); // Skip "otherwise"
-            Some(self.parse_program())
+            Some(self.parse_block())
         } else {
             None
         };
         Box::new(WhereStatement {
-            condition: Box::new(condition),
+            conditions,
             true_branch,
             false_branch,
         })
     }
 
+    /// Parses `loop`, with an optional label that a `break` inside it can
+    /// name to leave it from inside another loop.
+    /// 
+    /// Example:
+    /// ```clarice
+    /// loop as outer do
+    ///     iter x in [1, 2, 3] do
+    ///         where x in [2] then break outer
+    ///     end
+    /// end
+    /// ```
     fn parse_loop_statement(&mut self) -> Box<LoopStatement> {
         self.advance(); // Skip "loop"
-        let expression = self.parse_expression();
-        Box::new(LoopStatement {
-            expression: Box::new(expression),
+        let mut label = None;
+        if self.current_token == Token::Keyword(Keyword::As) {
+            self.advance(); // Skip "as"
+            match self.current_token {
+                Token::Identifier(ref id) => {
+                    label = Some(id.clone());
+                    self.advance();
+                }
+                _ => self.error(format!("Expected a label after 'loop as', got {:?}", self.current_token)),
+            }
+        }
+        let body = self.parse_block();
+        Box::new(LoopStatement { label, body })
+    }
+
+    fn parse_while_statement(&mut self) -> Box<WhileStatement> {
+        self.advance(); // Skip "while"
+        let condition = self.parse_expression();
+        let body = self.parse_block();
+        Box::new(WhileStatement {
+            condition: Box::new(condition),
+            body,
         })
     }
 
@@ -325,24 +665,529 @@ impl<'a> Parser<'a> {
         let variable = match self.current_token {
             Token::Identifier(ref id) => id.clone(),
             _ => {
-                println!("Expected identifier after 'iter' keyword, got {:?}", self.current_token);
+                self.error(format!("Expected identifier after 'iter' keyword, got {:?}", self.current_token));
                 return Box::new(IterStatement {
                     variable: "error".to_string(),
+                    value_variable: None,
                     iterable: Box::new(Expression::StringLiteral("No Iterable (Iter)".to_string())),
-                    expression: Box::new(Expression::StringLiteral("No Identifier (Iter).".to_string())),
+                    body: Vec::new(),
                 });
             }
         };
         self.advance(); // Advance to next token
-        let expression = self.parse_expression();
+        let mut value_variable = None;
+        if self.current_token == Token::Separator(",".to_string()) {
+            self.advance(); // Skip ","
+            match self.current_token {
+                Token::Identifier(ref id) => {
+                    value_variable = Some(id.clone());
+                    self.advance();
+                }
+                _ => self.error(format!("Expected identifier after ',' in 'iter', got {:?}", self.current_token)),
+            }
+        }
+        if self.current_token != Token::Keyword(Keyword::In) {
+            self.error(format!("Expected 'in' after identifier, got {:?}", self.current_token));
+        }
+        else {
+            self.advance(); // Skip "in"
+        }
+        let iterable = self.parse_expression();
+        let body = self.parse_block();
         Box::new(IterStatement {
             variable,
-            iterable: Box::new(self.parse_expression()),
-            expression: Box::new(expression),
+            value_variable,
+            iterable: Box::new(iterable),
+            body,
+        })
+    }
+
+    /// Parses the rest of a list comprehension after its element, from `for`
+    /// up to the closing `]`, with an optional `where` condition.
+    /// 
+    /// Example:
+    /// ```clarice
+    /// print [x * 2 for x in [1, 2, 3] where x in [1, 3]]
+    /// # -> [2, 6]
+    /// ```
+    fn parse_comprehension_rest(&mut self, element: Expression) -> Expression {
+        self.advance(); // Skip "for"
+        let variable = match self.current_token {
+            Token::Identifier(ref id) => id.clone(),
+            _ => {
+                self.error(format!("Expected identifier after 'for' in a list, got {:?}", self.current_token));
+                "error".to_string()
+            }
+        };
+        self.advance();
+        if self.current_token != Token::Keyword(Keyword::In) {
+            self.error(format!("Expected 'in' after identifier, got {:?}", self.current_token));
+        }
+        else {
+            self.advance(); // Skip "in"
+        }
+        let iterable = self.parse_expression();
+        let mut condition = None;
+        if self.current_token == Token::Keyword(Keyword::Where) {
+            self.advance(); // Skip "where"
+            condition = Some(self.parse_expression());
+        }
+        if self.current_token != Token::Separator("]".to_string()) {
+            self.error(format!("Expected ']' to close the list, got {:?}", self.current_token));
+        }
+        else {
+            self.advance(); // Skip "]"
+        }
+        Expression::Comprehension(Box::new(Comprehension { element, variable, iterable, condition }))
+    }
+
+    /// Parses `match value do`, followed by `case pattern` blocks and an
+    /// optional `otherwise` block, up to `end`.
+    /// 
+    /// Example:
+    /// ```clarice
+    /// match x do
+    ///     case 1 do
+    ///         print "one"
+    ///     case 2 then print "two"
+    ///     otherwise do
+    ///         print "something else"
+    /// end
+    /// ```
+    fn parse_match_statement(&mut self) -> Box<MatchStatement> {
+        self.advance(); // Skip "match"
+        let scrutinee = self.parse_expression();
+        if self.current_token != Token::Keyword(Keyword::Do) {
+            self.error(format!("Expected 'do' after the value to match, got {:?}", self.current_token));
+        }
+        else {
+            self.advance(); // Skip "do"
+        }
+        let mut cases = Vec::new();
+        let mut default = None;
+        // A `do` block takes the match's `end` with it, but a `then` block doesn't
+        let mut needs_end = true;
+        while self.current_token == Token::Keyword(Keyword::Case) {
+            self.advance(); // Skip "case"
+            let pattern = self.parse_expression();
+            needs_end = self.current_token == Token::Keyword(Keyword::Then);
+            cases.push((pattern, self.parse_block()));
+        }
+        if self.current_token == Token::Keyword(Keyword::Otherwise) {
+            self.advance(); // Skip "otherwise"
+            needs_end = self.current_token == Token::Keyword(Keyword::Then);
+            default = Some(self.parse_block());
+        }
+        if needs_end {
+            if self.current_token != Token::Keyword(Keyword::End) {
+                self.error(format!("Expected 'case', 'otherwise' or 'end' in `match`, got {:?}", self.current_token));
+            }
+            else {
+                self.advance(); // Skip "end"
+            }
+        }
+        Box::new(MatchStatement {
+            scrutinee: Box::new(scrutinee),
+            cases,
+            default,
+        })
+    }
+
+    /// Parses a function definition: `fn`, the function's name, its
+    /// parameters in brackets, and a block whose last statement gives the
+    /// function's result.
+    /// 
+    /// Example:
+    /// ```clarice
+    /// fn add(x, y) do
+    ///     x + y
+    /// end
+    /// print add 1 2
+    /// # -> 3
+    /// ```
+    fn parse_function_statement(&mut self) -> Box<FunctionStatement> {
+        self.advance(); // Skip "fn"
+        let name = match self.current_token {
+            Token::Identifier(ref id) => id.clone(),
+            _ => {
+                self.error(format!("Expected a function name after 'fn' keyword, got {:?}", self.current_token));
+                return Box::new(FunctionStatement {
+                    name: "error".to_string(),
+                    parameters: Vec::new(),
+                    parameter_types: Vec::new(),
+                    return_type: None,
+                    body: Vec::new(),
+                });
+            }
+        };
+        self.advance(); // Advance to next token
+        self.parse_function_rest(name)
+    }
+
+    /// Parses a function from its parameters onwards, which is the same for
+    /// a named function and a lambda.
+    fn parse_function_rest(&mut self, name: String) -> Box<FunctionStatement> {
+        let (parameters, parameter_types) = self.parse_parameters();
+        let return_type = self.parse_annotation();
+        let body = self.parse_block();
+        Box::new(FunctionStatement {
+            name,
+            parameters,
+            parameter_types,
+            return_type,
+            body,
         })
     }
 
+    /// Parses a function's parameter names, each with an optional type, e.g.
+    /// `(x: integer, y)`.
+    fn parse_parameters(&mut self) -> (Vec<String>, Vec<Option<Type>>) {
+        let mut parameters = Vec::new();
+        let mut parameter_types = Vec::new();
+        if self.current_token != Token::Separator("(".to_string()) {
+            self.error(format!("Expected '(' before the function's parameters, got {:?}", self.current_token));
+            return (parameters, parameter_types);
+        }
+        self.advance(); // Skip "("
+        while self.current_token != Token::Separator(")".to_string()) {
+            match self.current_token {
+                Token::Identifier(ref id) => parameters.push(id.clone()),
+                _ => {
+                    self.error(format!("Expected a parameter name, got {:?}", self.current_token));
+                    return (parameters, parameter_types);
+                }
+            }
+            self.advance(); // Advance past the parameter
+            parameter_types.push(self.parse_annotation());
+            if self.current_token == Token::Separator(",".to_string()) {
+                self.advance(); // Skip ","
+            }
+        }
+        self.advance(); // Skip ")"
+        (parameters, parameter_types)
+    }
+
+    /// Parses a type annotation, `:` followed by a type, if there is one.
+    fn parse_annotation(&mut self) -> Option<Type> {
+        if self.current_token != Token::Separator(":".to_string()) {
+            return None;
+        }
+        self.advance(); // Skip ":"
+        Some(self.parse_type())
+    }
+
+    /// Parses a type: `integer`, `double`, `string`, `boolean`, `any`, or a
+    /// list of one of those in square brackets, e.g. `[integer]`.
+    fn parse_type(&mut self) -> Type {
+        if !self.nest() {
+            return Type::Any;
+        }
+        let parsed_type = self.parse_type_kind();
+        self.depth -= 1;
+        parsed_type
+    }
+
+    fn parse_type_kind(&mut self) -> Type {
+        let token = self.current_token.clone();
+        self.advance(); // Advance past the type, or the "[" or "{" of a list or map type
+        match token {
+            Token::Identifier(ref name) => match name.as_str() {
+                "integer" => Type::Integer,
+                "double" => Type::Double,
+                "string" => Type::String,
+                "boolean" => Type::Boolean,
+                "any" => Type::Any,
+                _ => {
+                    self.error(format!(
+                        "Unknown type `{}` - expected integer, double, string, boolean, any, a list like [integer], or a map like {{string: integer}}",
+                        name
+                    ));
+                    Type::Any
+                }
+            },
+            Token::Separator(ref s) if s == "[" => {
+                let element_type = self.parse_type();
+                if self.current_token != Token::Separator("]".to_string()) {
+                    self.error(format!("Expected ']' to close the list type, got {:?}", self.current_token));
+                    return Type::List(Box::new(element_type));
+                }
+                self.advance(); // Skip "]"
+                Type::List(Box::new(element_type))
+            }
+            Token::Separator(ref s) if s == "{" => {
+                let key_type = self.parse_type();
+                if self.current_token != Token::Separator(":".to_string()) {
+                    self.error(format!("Expected ':' after the key type of the map type, got {:?}", self.current_token));
+                    return Type::Map(Box::new(key_type), Box::new(Type::Any));
+                }
+                self.advance(); // Skip ":"
+                let value_type = self.parse_type();
+                if self.current_token != Token::Separator("}".to_string()) {
+                    self.error(format!("Expected '}}' to close the map type, got {:?}", self.current_token));
+                    return Type::Map(Box::new(key_type), Box::new(value_type));
+                }
+                self.advance(); // Skip "}"
+                Type::Map(Box::new(key_type), Box::new(value_type))
+            }
+            _ => {
+                self.error(format!("Expected a type, got {:?}", token));
+                Type::Any
+            }
+        }
+    }
+
+    /// Parses the body of `where`, `otherwise`, `loop`, `while`, `iter` and
+    /// `case`: either `do` followed by statements up to `end` (or `otherwise`
+    /// or `case`), or `then` followed by a single statement.
+    fn parse_block(&mut self) -> Vec<Statement> {
+        if self.current_token == Token::Keyword(Keyword::Then) {
+            self.advance(); // Skip "then"
+            return vec![self.parse_statement()];
+        }
+        if self.current_token != Token::Keyword(Keyword::Do) {
+            self.error(format!("Expected 'do' or 'then' to start a block, got {:?}", self.current_token));
+            return Vec::new();
+        }
+        self.advance(); // Skip "do"
+        let mut statements = Vec::new();
+        while self.current_token != Token::Eof
+            && self.current_token != Token::Keyword(Keyword::End)
+            && self.current_token != Token::Keyword(Keyword::Otherwise)
+            && self.current_token != Token::Keyword(Keyword::Case)
+        {
+            if self.skip_separator() {
+                continue;
+            }
+            statements.append(&mut self.comments);
+            statements.push(self.parse_statement());
+        }
+        // Comments just before the `end` belong at the end of the block
+        statements.append(&mut self.comments);
+        if self.current_token == Token::Keyword(Keyword::End) {
+            self.advance(); // Skip "end"
+        }
+        statements
+    }
+
+    fn parse_import_statement(&mut self) -> Box<ImportStatement> {
+        self.advance(); // Skip "import"
+        let path = match self.current_token {
+            Token::StringLiteral(ref path) => path.clone(),
+            _ => {
+                self.error(format!("Expected the path of a file in quotes after 'import', got {:?}", self.current_token));
+                return Box::new(ImportStatement { path: String::new(), alias: None });
+            }
+        };
+        self.advance(); // Advance past the path
+        // An `as` on the next line starts an `as` statement instead
+        if self.current_token != Token::Keyword(Keyword::As) || self.starts_line {
+            return Box::new(ImportStatement { path, alias: None });
+        }
+        self.advance(); // Skip "as"
+        let alias = match self.current_token {
+            Token::Identifier(ref alias) => alias.clone(),
+            _ => {
+                self.error(format!("Expected a name for the import after 'as', got {:?}", self.current_token));
+                return Box::new(ImportStatement { path, alias: None });
+            }
+        };
+        self.advance(); // Advance past the alias
+        Box::new(ImportStatement { path, alias: Some(alias) })
+    }
+
+    fn parse_exit_statement(&mut self) -> Box<ExitStatement> {
+        self.advance(); // Skip "exit"
+        let code = if self.at_atom() {
+            Some(Box::new(self.parse_expression()))
+        } else {
+            None
+        };
+        Box::new(ExitStatement { code })
+    }
+
     fn parse_expression(&mut self) -> Expression {
+        if !self.nest() {
+            return Expression::Error;
+        }
+        let expression = if self.current_token == Token::Keyword(Keyword::If) {
+            self.parse_conditional()
+        }
+        else {
+            self.parse_default()
+        };
+        self.depth -= 1;
+        expression
+    }
+
+    /// Parses `if condition then a else b`, which gives `a` if the condition
+    /// holds and `b` otherwise. Both branches are expressions, so unlike
+    /// `where`, the `else` can't be left out.
+    fn parse_conditional(&mut self) -> Expression {
+        self.advance(); // Skip "if"
+        let condition = self.parse_expression();
+        if self.current_token != Token::Keyword(Keyword::Then) {
+            self.error(format!("Expected 'then' after the condition of `if`, got {:?}", self.current_token));
+            return condition;
+        }
+        self.advance(); // Skip "then"
+        let true_branch = self.parse_expression();
+        if self.current_token != Token::Keyword(Keyword::Else) {
+            self.error(format!("Expected 'else' after the `then` branch of `if`, got {:?}", self.current_token));
+            return true_branch;
+        }
+        self.advance(); // Skip "else"
+        let false_branch = self.parse_expression();
+        Expression::Conditional(Box::new(condition), Box::new(true_branch), Box::new(false_branch))
+    }
+
+    /// Parses `value or default`, which gives `default` if `value` is void.
+    fn parse_default(&mut self) -> Expression {
+        let mut left = self.parse_comparison();
+        while self.current_token == Token::Keyword(Keyword::Or) {
+            self.advance(); // Skip "or"
+            let right = self.parse_comparison();
+            left = Expression::BinaryOperation(Box::new(left), "or".to_string(), Box::new(right));
+        }
+        left
+    }
+
+    /// Parses `element in collection`, which checks whether a list contains
+    /// an element or a string contains a substring, or a comparison like
+    /// `x == y` or `x < y`. Neither chains, so `a < b < c` is an error.
+    fn parse_comparison(&mut self) -> Expression {
+        let left = self.parse_concatenation();
+        let operator = match &self.current_token {
+            Token::Keyword(Keyword::In) => "in".to_string(),
+            Token::Operator(operator) if COMPARISONS.contains(&operator.as_str()) => operator.clone(),
+            _ => return left,
+        };
+        self.advance(); // Skip the operator
+        let right = self.parse_concatenation();
+        Expression::BinaryOperation(Box::new(left), operator, Box::new(right))
+    }
+
+    /// Parses `..`, which joins strings together. It binds more loosely than
+    /// `+` and `-`, so `"n is " .. n + 1` adds before it joins.
+    fn parse_concatenation(&mut self) -> Expression {
+        let mut left = self.parse_additive();
+        while self.current_token == Token::Operator("..".to_string()) {
+            self.advance(); // Skip ".."
+            let right = self.parse_additive();
+            left = Expression::BinaryOperation(Box::new(left), "..".to_string(), Box::new(right));
+        }
+        left
+    }
+
+    fn parse_additive(&mut self) -> Expression {
+        let mut left = self.parse_multiplicative();
+        while let Token::Operator(ref operator) = self.current_token.clone() {
+            if operator != "+" && operator != "-" {
+                break;
+            }
+            self.advance(); // Skip the operator
+            let right = self.parse_multiplicative();
+            left = Expression::BinaryOperation(Box::new(left), operator.clone(), Box::new(right));
+        }
+        left
+    }
+
+    fn parse_multiplicative(&mut self) -> Expression {
+        let mut left = self.parse_cast();
+        while let Token::Operator(ref operator) = self.current_token.clone() {
+            if operator != "*" && operator != "/" {
+                break;
+            }
+            self.advance(); // Skip the operator
+            let right = self.parse_cast();
+            left = Expression::BinaryOperation(Box::new(left), operator.clone(), Box::new(right));
+        }
+        left
+    }
+
+    /// Parses a value converted to another type, e.g. `x as integer`. The
+    /// `as` has to be on the same line, as on a new line it starts an `as`
+    /// statement.
+    fn parse_cast(&mut self) -> Expression {
+        let mut expression = self.parse_application();
+        let depth = self.depth;
+        while self.current_token == Token::Keyword(Keyword::As) && !self.starts_line && self.nest() {
+            self.advance(); // Skip "as"
+            expression = Expression::Cast(Box::new(expression), self.parse_type());
+        }
+        self.depth = depth;
+        expression
+    }
+
+    fn parse_application(&mut self) -> Expression {
+        let token = self.current_token.clone();
+        match token {
+            Token::Identifier(ref id) => {
+                self.advance(); // Advance past the identifier
+                if self.at_index() {
+                    return self.parse_indexes(Expression::Identifier(id.clone()));
+                }
+                if !self.at_atom() || self.starts_line {
+                    return Expression::Identifier(id.clone());
+                }
+                // Arguments follow the function name directly, e.g. `double (str 3.14)`,
+                // on the same line
+                let mut arguments = Vec::new();
+                while self.at_atom() && !self.starts_line {
+                    arguments.push(self.parse_atom());
+                }
+                Expression::FunctionCall(id.clone(), arguments)
+            },
+            _ => self.parse_atom(),
+        }
+    }
+
+    /// Whether the current token can start an atom (and therefore a function
+    /// call argument).
+    fn at_atom(&self) -> bool {
+        matches!(
+            self.current_token,
+            Token::Identifier(_) | Token::IntegerLiteral(_) | Token::DoubleLiteral(_) | Token::StringLiteral(_)
+        ) || self.current_token == Token::Separator("(".to_string())
+            || self.current_token == Token::Separator("[".to_string())
+            || self.current_token == Token::Separator("{".to_string())
+    }
+
+    /// Whether the current token is the `fn` of a lambda, followed by its
+    /// parameters rather than a function name.
+    fn at_lambda(&self) -> bool {
+        self.current_token == Token::Keyword(Keyword::Fn) && self.lexer.next_char() == Some('(')
+    }
+
+    /// Whether the current token is a `[` right after the expression before
+    /// it, indexing into it. With a space before it, it starts a list.
+    fn at_index(&self) -> bool {
+        self.current_token == Token::Separator("[".to_string()) && !self.after_space
+    }
+
+    /// Parses any indexes after an expression, e.g. the `[0][1]` of
+    /// `grid[0][1]`.
+    fn parse_indexes(&mut self, mut expression: Expression) -> Expression {
+        let depth = self.depth;
+        while self.at_index() && self.nest() {
+            self.advance(); // Skip "["
+            let index = self.parse_expression();
+            if self.current_token != Token::Separator("]".to_string()) {
+                self.error(format!("Expected ']' after the index, got {:?}", self.current_token));
+                break;
+            }
+            self.advance(); // Skip "]"
+            expression = Expression::Index(Box::new(expression), Box::new(index));
+        }
+        self.depth = depth;
+        expression
+    }
+
+    fn parse_atom(&mut self) -> Expression {
+        let atom = self.parse_unindexed_atom();
+        self.parse_indexes(atom)
+    }
+
+    fn parse_unindexed_atom(&mut self) -> Expression {
         let token = self.current_token.clone();
         match token {
             Token::Identifier(ref id) => {
@@ -353,22 +1198,158 @@ impl<'a> Parser<'a> {
                 self.advance(); // Advance past the integer literal
                 Expression::IntegerLiteral(num)
             },
+            Token::DoubleLiteral(num) => {
+                self.advance(); // Advance past the double literal
+                Expression::DoubleLiteral(num)
+            },
             Token::StringLiteral(ref s) => {
                 self.advance(); // Advance past the string literal
                 Expression::StringLiteral(s.clone())
             },
+            Token::Separator(ref s) if s == "(" => {
+                self.advance(); // Skip "("
+                let expression = self.parse_expression();
+                if self.current_token != Token::Separator(")".to_string()) {
+                    self.error(format!("Expected ')' after expression, got {:?}", self.current_token));
+                    return expression;
+                }
+                self.advance(); // Skip ")"
+                expression
+            },
+            Token::Separator(ref s) if s == "[" => {
+                self.advance(); // Skip "["
+                let mut elements = Vec::new();
+                while self.current_token != Token::Separator("]".to_string()) {
+                    if matches!(self.current_token, Token::Keyword(_) | Token::Eof) {
+                        self.error(format!("Expected ']' to close the list, got {:?}", self.current_token));
+                        return Expression::ListLiteral(elements);
+                    }
+                    elements.push(self.parse_expression());
+                    if elements.len() == 1 && self.current_token == Token::Keyword(Keyword::For) {
+                        let element = elements.pop().unwrap();
+                        return self.parse_comprehension_rest(element);
+                    }
+                    if self.current_token == Token::Separator(",".to_string()) {
+                        self.advance(); // Skip ","
+                    }
+                }
+                self.advance(); // Skip "]"
+                Expression::ListLiteral(elements)
+            },
+            Token::Separator(ref s) if s == "{" => {
+                self.advance(); // Skip "{"
+                let mut entries = Vec::new();
+                while self.current_token != Token::Separator("}".to_string()) {
+                    if matches!(self.current_token, Token::Keyword(_) | Token::Eof) {
+                        self.error(format!("Expected '}}' to close the map, got {:?}", self.current_token));
+                        return Expression::MapLiteral(entries);
+                    }
+                    let key = self.parse_expression();
+                    if self.current_token != Token::Separator(":".to_string()) {
+                        self.error(format!("Expected ':' after a key in the map, got {:?}", self.current_token));
+                        return Expression::MapLiteral(entries);
+                    }
+                    self.advance(); // Skip ":"
+                    entries.push((key, self.parse_expression()));
+                    if self.current_token == Token::Separator(",".to_string()) {
+                        self.advance(); // Skip ","
+                    }
+                }
+                self.advance(); // Skip "}"
+                Expression::MapLiteral(entries)
+            },
+            Token::Keyword(Keyword::Do) => {
+                // A `do` block used as a value gives the value of its last statement
+                let statements = self.parse_block();
+                Expression::Block(statements)
+            },
+            Token::Keyword(Keyword::Fn) => {
+                self.advance(); // Skip "fn"
+                // Lambdas are called `fn`, which can't clash with a variable as it's a keyword
+                Expression::Lambda(self.parse_function_rest(Keyword::Fn.to_string()))
+            },
             _ => {
-                println!("Expected an expression, got {:?}", self.current_token);
-                self.advance();
-                Expression::StringLiteral("No Expression.".to_string())
+                self.error(format!("Expected an expression, got {:?}", self.current_token));
+                // Keywords are left for the statement they belong to, such as the `end` of a block
+                if !matches!(self.current_token, Token::Keyword(_) | Token::Eof) {
+                    self.advance();
+                }
+                Expression::Error
             }
         }
     }
     
-    pub fn parse(&mut self) -> Result<ASTNode, String> {
-        let program = self.parse_program();
-        let mut type_checker = TypeChecker::new();
+    pub fn parse(&mut self, type_checker: &mut TypeChecker) -> Result<ASTNode, Vec<ClariceError>> {
+        let mut program = self.parse_only()?;
         type_checker.check(&program)?;
+        fold_constants(&mut program).map_err(|e| vec![ClariceError::new(e)])?;
         Ok(program)
     }
+
+    /// Parses a single expression with nothing after it, for `:type` in the
+    /// interactive mode. Its constants aren't folded.
+    pub fn parse_single_expression(&mut self) -> Result<Expression, Vec<ClariceError>> {
+        let expression = self.parse_expression();
+        if self.current_token != Token::Eof {
+            self.error(format!("Expected a single expression, but it carries on with {:?}", self.current_token));
+        }
+        if self.errors.is_empty() {
+            Ok(expression)
+        }
+        else {
+            Err(std::mem::take(&mut self.errors))
+        }
+    }
+
+    /// Parses the program without type checking it, for `--no-typecheck`.
+    pub fn parse_only(&mut self) -> Result<ASTNode, Vec<ClariceError>> {
+        let program = self.parse_program();
+        if self.errors.is_empty() {
+            Ok(program)
+        }
+        else {
+            Err(std::mem::take(&mut self.errors))
+        }
+    }
+}
+
+/// Works out which file `import path` means. A relative path is relative to
+/// the directory of the file doing the importing, if there is one. The path
+/// is made canonical, so that the same file always gets the same path.
+pub fn resolve_import(path: &str, importer: Option<&Path>) -> Result<PathBuf, String> {
+    let directory = importer.and_then(Path::parent).unwrap_or(Path::new(""));
+    directory.join(path)
+        .canonicalize()
+        .map_err(|e| format!("Couldn't import \"{}\": {}", path, e))
+}
+
+/// Reads and parses a file for `import`, folding its constants but leaving
+/// type checking to the caller.
+pub fn parse_import(path: &Path) -> Result<ASTNode, String> {
+    let input = std::fs::read_to_string(path)
+        .map_err(|e| format!("Couldn't import {}: {}", path.display(), e))?;
+    let in_file = |e: String| format!("In {}: {}", path.display(), e);
+    let mut program = Parser::new(Lexer::new(&input))
+        .parse_only()
+        .map_err(|errors| {
+            let errors: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+            in_file(errors.join("\n"))
+        })?;
+    fold_constants(&mut program).map_err(in_file)?;
+    Ok(program)
+}
+
+/// Stops a file from importing itself, directly or through other files.
+/// `imports` are the files being imported, innermost last.
+pub fn check_import_cycle(imports: &[PathBuf], path: &Path) -> Result<(), String> {
+    match imports.iter().position(|import| import == path) {
+        Some(start) => {
+            let chain: Vec<String> = imports[start..].iter()
+                .chain(std::iter::once(&path.to_path_buf()))
+                .map(|import| import.display().to_string())
+                .collect();
+            Err(format!("Circular import: {}", chain.join(" imports ")))
+        }
+        None => Ok(()),
+    }
 }
@@ -8,9 +8,48 @@ pub enum Type {
     Double,
     String,
     Boolean,
-    Closure,
-    List,
+    Function(Vec<Type>, Box<Type>), // Parameter types and return type
+    List(Box<Type>),
+    Map(Box<Type>, Box<Type>), // Key type and value type
     Void,
+    Any, // The elements of an empty list could be anything
+}
+
+impl Type {
+    /// Whether a value of type `other` can be used where `self` is expected.
+    pub fn accepts(&self, other: &Type) -> bool {
+        match (self, other) {
+            (Type::Any, _) | (_, Type::Any) => true,
+            (Type::List(a), Type::List(b)) => a.accepts(b),
+            (Type::Map(a_key, a_value), Type::Map(b_key, b_value)) => a_key.accepts(b_key) && a_value.accepts(b_value),
+            (Type::Function(a_parameters, a_return), Type::Function(b_parameters, b_return)) => {
+                a_parameters.len() == b_parameters.len()
+                    && a_parameters.iter().zip(b_parameters).all(|(a, b)| a.accepts(b))
+                    && a_return.accepts(b_return)
+            }
+            _ => self == other,
+        }
+    }
+}
+
+/// Writes a type the way a type annotation spells it, e.g. `[integer]`.
+impl std::fmt::Display for Type {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Type::Integer => write!(f, "integer"),
+            Type::Double => write!(f, "double"),
+            Type::String => write!(f, "string"),
+            Type::Boolean => write!(f, "boolean"),
+            Type::Function(parameter_types, return_type) => {
+                let parameter_types: Vec<String> = parameter_types.iter().map(Type::to_string).collect();
+                write!(f, "fn({}) -> {}", parameter_types.join(", "), return_type)
+            }
+            Type::List(element_type) => write!(f, "[{}]", element_type),
+            Type::Map(key_type, value_type) => write!(f, "{{{}: {}}}", key_type, value_type),
+            Type::Void => write!(f, "void"),
+            Type::Any => write!(f, "any"),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -19,27 +58,66 @@ pub struct Symbol {
     pub symbol_type: Type,
 }
 
-#[derive(Debug)]
+/// A stack of scopes, innermost last. The first scope is the global scope,
+/// which is never popped.
+#[derive(Debug, Clone)]
 pub struct SymbolTable {
-    symbols: HashMap<String, Symbol>,
+    scopes: Vec<HashMap<String, Symbol>>,
 }
 
 impl SymbolTable {
     pub fn new() -> Self {
         SymbolTable {
-            symbols: HashMap::new(),
+            scopes: vec![HashMap::new()],
+        }
+    }
+
+    pub fn push_scope(&mut self) {
+        self.scopes.push(HashMap::new());
+    }
+
+    pub fn pop_scope(&mut self) {
+        if self.scopes.len() > 1 {
+            self.scopes.pop();
         }
     }
 
+    /// Binds a name in the innermost scope.
     pub fn insert(&mut self, name: String, symbol_type: Type) {
         let symbol = Symbol {
             name: name.clone(),
             symbol_type
         };
-        self.symbols.insert(name, symbol);
+        self.scopes.last_mut().unwrap().insert(name, symbol);
+    }
+
+    /// Updates a name in the scope that already binds it. A new name goes in
+    /// the global scope, as variables made by `set` outlive the block they
+    /// were made in.
+    pub fn assign(&mut self, name: String, symbol_type: Type) {
+        let symbol = Symbol {
+            name: name.clone(),
+            symbol_type
+        };
+        let scope = match self.scopes.iter().rposition(|scope| scope.contains_key(&name)) {
+            Some(index) => &mut self.scopes[index],
+            None => &mut self.scopes[0],
+        };
+        scope.insert(name, symbol);
     }
 
     pub fn lookup(&self, name: &str) -> Option<&Symbol> {
-        self.symbols.get(name)
+        self.scopes.iter().rev().find_map(|scope| scope.get(name))
     }
-}
\ No newline at end of file
+
+    /// The names bound in the global scope.
+    pub fn globals(&self) -> impl Iterator<Item = &Symbol> {
+        self.scopes[0].values()
+    }
+}
+
+impl Default for SymbolTable {
+    fn default() -> Self {
+        SymbolTable::new()
+    }
+}
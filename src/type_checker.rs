@@ -1,87 +1,428 @@
 // type_checker.rs - Type checker for the Clarice programming language
 
-use crate::parser::{ASTNode, Expression, Statement};
+use crate::error::ClariceError;
+use std::path::PathBuf;
+
+use crate::parser::{check_import_cycle, parse_import, resolve_import, ASTNode, Expression, FunctionStatement, Statement, StatementKind};
 use crate::symbol_table::{SymbolTable, Type};
 
+#[derive(Clone)]
 pub struct TypeChecker {
+    /// Lets conditions be any value rather than only booleans, to match the
+    /// interpreter's truthiness mode.
+    pub truthiness: bool,
+    /// Only lets `..` join strings, to match the interpreter's strict
+    /// coercion mode.
+    pub strict_coercion: bool,
+    /// The files being imported, innermost last, starting with the file being
+    /// checked if there is one. Imports are found relative to the last one.
+    pub imports: Vec<PathBuf>,
     symbol_table: SymbolTable,
+    errors: Vec<ClariceError>,
+    loops: Vec<Option<String>>, // The labels of the loops the statement being checked is inside
 }
 
 impl TypeChecker {
     pub fn new() -> Self {
         TypeChecker {
-            symbol_table: SymbolTable::new(),
+            truthiness: false,
+            strict_coercion: false,
+            imports: Vec::new(),
+            symbol_table: builtins(),
+            errors: Vec::new(),
+            loops: Vec::new(),
         }
     }
 
-    pub fn check(&mut self, program: &ASTNode) -> Result<(), String> {
+    /// Declares a variable that the program will be given when it runs, such
+    /// as `args`.
+    pub fn declare(&mut self, name: String, symbol_type: Type) {
+        self.symbol_table.assign(name, symbol_type);
+    }
+
+    /// Works out the type of an expression on its own, for `:type` in the
+    /// interactive mode. Nothing is run, and nothing the expression binds is
+    /// kept.
+    pub fn type_of(&self, expression: &Expression) -> Result<Type, Vec<ClariceError>> {
+        let mut type_checker = self.clone();
+        match type_checker.expression_type(expression) {
+            Ok(expression_type) if type_checker.errors.is_empty() => Ok(expression_type),
+            Ok(_) => Err(type_checker.errors),
+            Err(message) => {
+                type_checker.errors.push(ClariceError::new(message));
+                Err(type_checker.errors)
+            }
+        }
+    }
+
+    /// Checks a whole program, carrying on past errors so that they can all
+    /// be reported at once.
+    pub fn check(&mut self, program: &ASTNode) -> Result<(), Vec<ClariceError>> {
         match program {
             ASTNode::Program(statements) => {
-                for statement in statements {
-                    self.check_statement(statement);
-                }
-            }
-            _ => {
-                return Err("Expected program - got something else".to_string());
+                self.check_block(statements);
             }
         }
-        Ok(())
+        if self.errors.is_empty() {
+            Ok(())
+        }
+        else {
+            Err(std::mem::take(&mut self.errors))
+        }
     }
 
-    fn check_statement(&mut self, statement: &Statement) -> Result<(), String> {
-        match statement {
-            Statement::Set(set_statement) => {
+    /// Checks a statement, giving back the type of its value. Only expressions,
+    /// and `where` and `match` statements whose blocks end in one, have
+    /// values - anything else is void.
+    fn check_statement(&mut self, statement: &Statement) -> Result<Type, String> {
+        match &statement.kind {
+            StatementKind::Set(set_statement) if !set_statement.indexes.is_empty() => {
+                let mut target_type = match self.symbol_table.lookup(&set_statement.variable) {
+                    Some(symbol) => symbol.symbol_type.clone(),
+                    None => return Err(format!("Undefined variable `{}`", set_statement.variable)),
+                };
+                if target_type == Type::String {
+                    return Err("A string can't be changed a character at a time - make a new string instead".to_string());
+                }
+                for index in &set_statement.indexes {
+                    target_type = self.index_type(target_type, index)?;
+                }
+                let value_type = self.check_expression(&set_statement.expression)?;
+                if !target_type.accepts(&value_type) {
+                    return Err(format!(
+                        "`{}[...]` holds {:?}, so it can't be set to {:?}{}",
+                        set_statement.variable, target_type, value_type, near_miss(&target_type, &value_type)
+                    ));
+                }
+                Ok(Type::Void)
+            }
+            StatementKind::Set(set_statement) => {
                 let expression_type = self.check_expression(&set_statement.expression);
-                self.symbol_table.insert(set_statement.variable.clone(), expression_type?);
-                Ok(())
+                // Still bind the name if its value has an error, so every use of it doesn't become another error
+                self.symbol_table.assign(set_statement.variable.clone(), expression_type.clone().unwrap_or(Type::Any));
+                expression_type.map(|_| Type::Void)
+            }
+            StatementKind::Destructure(destructure_statement) => {
+                let element_type = match self.check_expression(&destructure_statement.expression) {
+                    Ok(Type::List(element_type)) => Ok(*element_type),
+                    Ok(Type::Any) => Ok(Type::Any),
+                    Ok(other) => Err(format!("`set [...]` expects a list, got {:?}", other)),
+                    Err(e) => Err(e),
+                };
+                // The length of a list can only be known here if it's written out
+                let element_type = match (&*destructure_statement.expression, element_type) {
+                    (Expression::ListLiteral(elements), Ok(_)) if elements.len() != destructure_statement.variables.len() => {
+                        Err(format!(
+                            "`set [...]` has {} variable(s), but the list has {} element(s)",
+                            destructure_statement.variables.len(), elements.len()
+                        ))
+                    }
+                    (_, element_type) => element_type,
+                };
+                for variable in &destructure_statement.variables {
+                    self.symbol_table.assign(variable.clone(), element_type.clone().unwrap_or(Type::Any));
+                }
+                element_type.map(|_| Type::Void)
             }
-            Statement::As(as_statement) => {
+            StatementKind::As(as_statement) => {
                 let expression_type = self.check_expression(&as_statement.expression);
-                self.symbol_table.insert(as_statement.identifier.clone(), expression_type?);
-                Ok(())
+                self.symbol_table.insert(as_statement.identifier.clone(), expression_type.clone().unwrap_or(Type::Any));
+                expression_type.map(|_| Type::Void)
             }
-            Statement::To(to_statement) => {
+            StatementKind::To(to_statement) => {
                 let expression_type = self.check_expression(&to_statement.expression);
-                self.symbol_table.insert(to_statement.identifier.clone(), expression_type?);
-                Ok(())
+                self.symbol_table.insert(to_statement.identifier.clone(), expression_type.clone().unwrap_or(Type::Any));
+                expression_type.map(|_| Type::Void)
             }
-            Statement::Then(then_statement) => {
-                self.check_statement(&then_statement.statement);
-                Ok(())
+            StatementKind::Then(then_statement) => {
+                self.check_statement(&then_statement.statement)
             }
-            Statement::Do(do_statement) => {
-                self.check_expression(&do_statement.expression);
-                Ok(())
+            StatementKind::Do(do_statement) => {
+                self.check_expression(&do_statement.expression)?;
+                Ok(Type::Void)
             }
-            Statement::Print(print_statement) => {
-                self.check_expression(&print_statement.expression);
-                Ok(())
+            StatementKind::Print(print_statement) => {
+                self.check_expression(&print_statement.expression)?;
+                Ok(Type::Void)
             }
-            Statement::With(with_statement) => {
-                self.check_expression(&with_statement.expression);
-                Ok(())
+            StatementKind::With(with_statement) => {
+                // The binding only lasts for the body, shadowing any outer one until then
+                let expression_type = self.check_expression(&with_statement.expression);
+                let expression_type = self.record(expression_type, statement.line).unwrap_or(Type::Any);
+                self.symbol_table.push_scope();
+                self.symbol_table.insert(with_statement.identifier.clone(), expression_type);
+                let body_type = match &with_statement.body {
+                    Some(body) => {
+                        let body_type = self.check_statement(body);
+                        Ok(self.record(body_type, body.line).unwrap_or(Type::Any))
+                    }
+                    None => Ok(Type::Void),
+                };
+                self.symbol_table.pop_scope();
+                body_type
             }
-            Statement::Where(where_statement) => {
-                self.check_expression(&where_statement.condition);
-                Ok(())
+            StatementKind::Where(where_statement) => {
+                // The branches are still checked if a condition has an error
+                for condition in &where_statement.conditions {
+                    let condition = self.check_condition(condition);
+                    self.record(condition, statement.line);
+                }
+                let true_type = self.check_block(&where_statement.true_branch);
+                match &where_statement.false_branch {
+                    Some(false_branch) => {
+                        let false_type = self.check_block(false_branch);
+                        Ok(common_type(true_type, false_type))
+                    }
+                    None => Ok(Type::Void),
+                }
+            }
+            StatementKind::Loop(loop_statement) => {
+                self.check_loop_body(loop_statement.label.as_ref(), &loop_statement.body);
+                Ok(Type::Void)
+            }
+            StatementKind::While(while_statement) => {
+                let condition = self.check_condition(&while_statement.condition);
+                self.record(condition, statement.line);
+                self.check_loop_body(None, &while_statement.body);
+                Ok(Type::Void)
+            }
+            StatementKind::Iter(iter_statement) => {
+                let iterable_type = self.check_expression(&iter_statement.iterable);
+                let types = match (iterable_type, &iter_statement.value_variable) {
+                    (Ok(Type::Map(key_type, value_type)), _) => Ok((*key_type, *value_type)),
+                    (Ok(other), Some(_)) => Err(format!("Only a map can be iterated with two variables, not {:?}", other)),
+                    (Ok(other), None) => iteration_type(other).map(|element_type| (element_type, Type::Void)),
+                    (Err(e), _) => Err(e),
+                };
+                let (element_type, value_type) = self.record(types, statement.line).unwrap_or((Type::Any, Type::Any));
+                self.symbol_table.push_scope();
+                self.symbol_table.insert(iter_statement.variable.clone(), element_type);
+                if let Some(value_variable) = &iter_statement.value_variable {
+                    self.symbol_table.insert(value_variable.clone(), value_type);
+                }
+                self.check_loop_body(None, &iter_statement.body);
+                self.symbol_table.pop_scope();
+                Ok(Type::Void)
+            }
+            StatementKind::Exit(exit_statement) => {
+                if let Some(code) = &exit_statement.code {
+                    let code_type = self.check_expression(code)?;
+                    if code_type != Type::Integer {
+                        return Err(format!("`exit` expects an integer status code, got {:?}", code_type));
+                    }
+                }
+                Ok(Type::Void)
+            }
+            StatementKind::Match(match_statement) => {
+                // The cases are still checked if the value being matched has an error
+                let scrutinee_type = self.check_expression(&match_statement.scrutinee);
+                let scrutinee_type = self.record(scrutinee_type, statement.line).unwrap_or(Type::Any);
+                let mut match_type = None;
+                for (pattern, body) in &match_statement.cases {
+                    let pattern_type = match self.check_expression(pattern) {
+                        Ok(pattern_type) if !scrutinee_type.accepts(&pattern_type) => Err(format!(
+                            "`case` pattern of type {:?} can never match a value of type {:?}",
+                            pattern_type, scrutinee_type
+                        )),
+                        result => result,
+                    };
+                    self.record(pattern_type, statement.line);
+                    let case_type = self.check_block(body);
+                    match_type = Some(match match_type {
+                        Some(match_type) => common_type(match_type, case_type),
+                        None => case_type,
+                    });
+                }
+                match (&match_statement.default, match_type) {
+                    (Some(default), Some(match_type)) => {
+                        let default_type = self.check_block(default);
+                        Ok(common_type(match_type, default_type))
+                    }
+                    (Some(default), None) => Ok(self.check_block(default)),
+                    // Without `otherwise`, there might not be a value
+                    (None, _) => Ok(Type::Void),
+                }
+            }
+            StatementKind::Function(function_statement) => {
+                self.check_function(function_statement, true).map(|_| Type::Void)
             }
-            Statement::Loop(loop_statement) => {
-                self.check_expression(&loop_statement.expression);
-                Ok(())
+            StatementKind::Break(_) if self.loops.is_empty() => {
+                Err("`break` can only be used inside a loop".to_string())
             }
-            Statement::Iter(iter_statement) => {
-                self.check_expression(&iter_statement.expression);
-                Ok(())
+            StatementKind::Break(Some(label)) if !self.loops.contains(&Some(label.clone())) => {
+                Err(format!("There's no loop called `{}` for `break` to leave", label))
             }
-            _ => {
-                Err((format!("Undefined statement `{:?}`", statement)).to_string())
+            StatementKind::Next if self.loops.is_empty() => Err("`next` can only be used inside a loop".to_string()),
+            StatementKind::Break(_) | StatementKind::Next | StatementKind::Pass | StatementKind::Comment(..) => Ok(Type::Void),
+            StatementKind::Error => unreachable!("a program with parse errors is never checked"),
+            StatementKind::Import(import_statement) => {
+                let path = resolve_import(&import_statement.path, self.imports.last().map(PathBuf::as_path))?;
+                check_import_cycle(&self.imports, &path)?;
+                let ASTNode::Program(statements) = parse_import(&path)?;
+                // The imported file is checked as if it were written here, so
+                // that its definitions can be used afterwards
+                let errors = self.errors.len();
+                let loops = std::mem::take(&mut self.loops);
+                self.imports.push(path);
+                // With `as`, the file gets names of its own, which are then given
+                // that name as a prefix
+                let importer = import_statement.alias.as_ref().map(|_| std::mem::replace(&mut self.symbol_table, builtins()));
+                self.check_block(&statements);
+                if let (Some(alias), Some(importer)) = (&import_statement.alias, importer) {
+                    let imported = std::mem::replace(&mut self.symbol_table, importer);
+                    let builtins = builtins();
+                    for symbol in imported.globals() {
+                        let is_builtin = builtins.lookup(&symbol.name)
+                            .is_some_and(|builtin| builtin.symbol_type == symbol.symbol_type);
+                        if !is_builtin {
+                            self.symbol_table.assign(format!("{}.{}", alias, symbol.name), symbol.symbol_type.clone());
+                        }
+                    }
+                }
+                let path = self.imports.pop().unwrap_or_default();
+                self.loops = loops;
+                for error in &mut self.errors[errors..] {
+                    error.message = format!("In {}: {}", path.display(), error);
+                    error.line = None;
+                }
+                Ok(Type::Void)
+            }
+            // A bare expression's value might be thrown away, so it can be void
+            StatementKind::Expression(expression) => self.expression_type(expression),
+        }
+    }
+
+    /// The type of an element of a list or string, or a value of a map, found
+    /// by indexing into something of type `target_type`.
+    fn index_type(&mut self, target_type: Type, index: &Expression) -> Result<Type, String> {
+        let index_type = self.check_expression(index)?;
+        let (key_type, element_type) = match target_type {
+            Type::List(element_type) => (Type::Integer, *element_type),
+            Type::String => (Type::Integer, Type::String),
+            Type::Map(key_type, value_type) => (*key_type, *value_type),
+            Type::Any => return Ok(Type::Any),
+            other => return Err(format!("Cannot index into {:?}", other)),
+        };
+        if !key_type.accepts(&index_type) {
+            let what = if key_type == Type::Integer { "An index" } else { "A key for this map" };
+            return Err(format!(
+                "{} has to be {:?}, got {:?}{}",
+                what, key_type, index_type, near_miss(&key_type, &index_type)
+            ));
+        }
+        Ok(element_type)
+    }
+
+    /// Whether `name` is still the built-in function, rather than something
+    /// the program has defined with the same name.
+    fn is_builtin(&self, name: &str) -> bool {
+        let symbol = self.symbol_table.lookup(name);
+        builtins().lookup(name).is_some_and(|builtin| symbol.is_some_and(|s| s.symbol_type == builtin.symbol_type))
+    }
+
+    /// Checks a function's body, and gives back the function's type. A named
+    /// function is bound to its name before its body is checked, so that it
+    /// can call itself.
+    fn check_function(&mut self, function_statement: &FunctionStatement, named: bool) -> Result<Type, String> {
+        // Parameters without type annotations could be anything
+        let parameters: Vec<Type> = function_statement.parameter_types.iter()
+            .map(|parameter_type| parameter_type.clone().unwrap_or(Type::Any))
+            .collect();
+        let annotated_return = function_statement.return_type.clone();
+        if named {
+            let function_type = Type::Function(parameters.clone(), Box::new(annotated_return.clone().unwrap_or(Type::Any)));
+            self.symbol_table.assign(function_statement.name.clone(), function_type);
+        }
+        self.symbol_table.push_scope();
+        for (parameter, parameter_type) in function_statement.parameters.iter().zip(&parameters) {
+            self.symbol_table.insert(parameter.clone(), parameter_type.clone());
+        }
+        // A `break` can't leave a loop from inside a function called in it
+        let loops = std::mem::take(&mut self.loops);
+        let return_type = self.check_block(&function_statement.body);
+        self.loops = loops;
+        self.symbol_table.pop_scope();
+        let function_type = Type::Function(parameters, Box::new(annotated_return.clone().unwrap_or(return_type.clone())));
+        if named {
+            self.symbol_table.assign(function_statement.name.clone(), function_type.clone());
+        }
+        match annotated_return {
+            Some(annotated) if !annotated.accepts(&return_type) => Err(format!(
+                "`{}` should give back {:?}, but its body gives back {:?}{}",
+                function_statement.name, annotated, return_type, near_miss(&annotated, &return_type)
+            )),
+            _ => Ok(function_type),
+        }
+    }
+
+    /// Checks the condition of a `where`, `while` or `if`, which has to be a
+    /// boolean unless truthiness mode is on.
+    fn check_condition(&mut self, condition: &Expression) -> Result<(), String> {
+        let condition_type = self.check_expression(condition)?;
+        if !self.truthiness && !Type::Boolean.accepts(&condition_type) {
+            return Err(format!(
+                "A condition has to be a boolean, got {:?} - pass `--truthiness` to let any value be a condition",
+                condition_type
+            ));
+        }
+        Ok(())
+    }
+
+    /// Checks each statement in a block, recording any errors and moving on to
+    /// the next statement. Gives back the type of the block's value, which is
+    /// the type of its last statement if that's an expression.
+    fn check_block(&mut self, statements: &[Statement]) -> Type {
+        let mut block_type = Type::Void;
+        for statement in statements {
+            let result = self.check_statement(statement);
+            block_type = self.record(result, statement.line).unwrap_or(Type::Any);
+        }
+        block_type
+    }
+
+    fn check_loop_body(&mut self, label: Option<&String>, statements: &[Statement]) {
+        self.loops.push(label.cloned());
+        self.check_block(statements);
+        self.loops.pop();
+    }
+
+    /// Records the error from a result, if there is one, as being on the
+    /// line of the statement it came from, and gives back the successful
+    /// value.
+    fn record<T>(&mut self, result: Result<T, String>, line: usize) -> Option<T> {
+        match result {
+            Ok(value) => Some(value),
+            Err(message) => {
+                self.errors.push(ClariceError::at_line(message, line));
+                None
             }
         }
     }
 
+    /// Checks an expression whose value is going to be used, so it can't be
+    /// void, as the result of `do print "hi" end` is.
     fn check_expression(&mut self, expression: &Expression) -> Result<Type, String> {
+        let expression_type = self.expression_type(expression)?;
+        if expression_type != Type::Void {
+            return Ok(expression_type);
+        }
+        let source = match expression {
+            Expression::FunctionCall(name, _) => format!("`{}`", name),
+            Expression::Block(_) => "this `do` block".to_string(),
+            _ => "this expression".to_string(),
+        };
+        Err(format!(
+            "{} gives nothing back, so its result can't be used - its last statement needs to be an expression, not a statement like `print` or `set`",
+            source
+        ))
+    }
+
+    /// Works out the type of an expression, which is void for a `do` block
+    /// or function that doesn't end in an expression.
+    fn expression_type(&mut self, expression: &Expression) -> Result<Type, String> {
         match expression {
             Expression::IntegerLiteral(_) => Ok(Type::Integer),
+            Expression::DoubleLiteral(_) => Ok(Type::Double),
             Expression::StringLiteral(_) => Ok(Type::String),
             Expression::BooleanLiteral(_) => Ok(Type::Boolean),
             Expression::Identifier(name) => {
@@ -92,7 +433,503 @@ impl TypeChecker {
                     Err(format!("Undefined variable `{}`", name))
                 }
             }
-            _ => Err((format!("Invalid expression `{:?}`", expression)).to_string()),
+            Expression::ListLiteral(elements) => {
+                let mut element_type = Type::Any;
+                for element in elements {
+                    let next_type = self.check_expression(element)?;
+                    if !element_type.accepts(&next_type) {
+                        return Err(format!(
+                            "List mixes elements of type {:?} and {:?}{}",
+                            element_type, next_type, near_miss(&element_type, &next_type)
+                        ));
+                    }
+                    if element_type == Type::Any {
+                        element_type = next_type;
+                    }
+                }
+                Ok(Type::List(Box::new(element_type)))
+            }
+            Expression::Error => unreachable!("a program with parse errors is never checked"),
+            Expression::Comprehension(comprehension) => {
+                let iterable_type = self.check_expression(&comprehension.iterable)?;
+                self.symbol_table.push_scope();
+                self.symbol_table.insert(comprehension.variable.clone(), iteration_type(iterable_type)?);
+                let result = comprehension.condition.as_ref()
+                    .map_or(Ok(()), |condition| self.check_condition(condition))
+                    .and_then(|()| self.check_expression(&comprehension.element));
+                self.symbol_table.pop_scope();
+                Ok(Type::List(Box::new(result?)))
+            }
+            Expression::BinaryOperation(..) => {
+                // A chain like `1 + 2 + 3` groups to the left, so it's worked out
+                // from its innermost operand outwards rather than recursing once
+                // per operator
+                let mut operations = Vec::new();
+                let mut left = expression;
+                while let Expression::BinaryOperation(inner, operator, right) = left {
+                    operations.push((operator.as_str(), right.as_ref()));
+                    left = inner;
+                }
+                // The value being defaulted is allowed to be void, as that's when the default is
+                // used, and so is a variable that isn't defined, which is void when it's run
+                let defaulted = operations.last().is_some_and(|(operator, _)| *operator == "or");
+                let mut left_type = match left {
+                    Expression::Identifier(name) if defaulted && self.symbol_table.lookup(name).is_none() => Type::Void,
+                    left if defaulted => self.expression_type(left)?,
+                    left => self.check_expression(left)?,
+                };
+                for (operator, right) in operations.into_iter().rev() {
+                    left_type = self.operation_type(left_type, operator, right)?;
+                }
+                Ok(left_type)
+            }
+            // Errors inside the block are recorded there, so they aren't errors of the block itself
+            Expression::Block(statements) => Ok(self.check_block(statements)),
+            Expression::Lambda(function_statement) => self.check_function(function_statement, false),
+            Expression::Index(expression, index) => {
+                let target_type = self.check_expression(expression)?;
+                self.index_type(target_type, index)
+            }
+            Expression::MapLiteral(entries) => {
+                let mut key_type = Type::Any;
+                let mut value_type = Type::Any;
+                for (key, value) in entries {
+                    let next_key_type = self.check_expression(key)?;
+                    if !is_key(&next_key_type) {
+                        return Err(format!(
+                            "{:?} can't be a key in a map - only integers, doubles, strings and booleans can",
+                            next_key_type
+                        ));
+                    }
+                    let next_value_type = self.check_expression(value)?;
+                    let kinds = [(&mut key_type, next_key_type, "keys"), (&mut value_type, next_value_type, "values")];
+                    for (known, next, what) in kinds {
+                        if !known.accepts(&next) {
+                            return Err(format!(
+                                "Map mixes {} of type {:?} and {:?}{}",
+                                what, known, next, near_miss(known, &next)
+                            ));
+                        }
+                        if *known == Type::Any {
+                            *known = next;
+                        }
+                    }
+                }
+                Ok(Type::Map(Box::new(key_type), Box::new(value_type)))
+            }
+            Expression::Cast(expression, target) => {
+                let source = self.check_expression(expression)?;
+                if !can_cast(&source, target) {
+                    return Err(format!("Cannot convert {:?} to {:?}", source, target));
+                }
+                Ok(target.clone())
+            }
+            Expression::Conditional(condition, true_branch, false_branch) => {
+                self.check_condition(condition)?;
+                let true_type = self.check_expression(true_branch)?;
+                let false_type = self.check_expression(false_branch)?;
+                if !true_type.accepts(&false_type) {
+                    return Err(format!(
+                        "The branches of `if` give different types, {:?} and {:?}",
+                        true_type, false_type
+                    ));
+                }
+                Ok(common_type(true_type, false_type))
+            }
+            Expression::FunctionCall(name, arguments) => {
+                let (parameters, return_type) = match self.symbol_table.lookup(name) {
+                    Some(symbol) => match &symbol.symbol_type {
+                        Type::Function(parameters, return_type) => (parameters.clone(), *return_type.clone()),
+                        // Such as a parameter, which might be given a function
+                        Type::Any => (vec![Type::Any; arguments.len()], Type::Any),
+                        other => return Err(format!("`{}` is {:?}, not a function", name, other)),
+                    },
+                    None => return Err(format!("Undefined function `{}`", name)),
+                };
+                if arguments.len() != parameters.len() {
+                    return Err(format!(
+                        "`{}` takes {} argument(s), but was given {}",
+                        name, parameters.len(), arguments.len()
+                    ));
+                }
+                let mut argument_types = Vec::new();
+                for (argument, parameter) in arguments.iter().zip(&parameters) {
+                    let argument_type = self.check_expression(argument)?;
+                    if !parameter.accepts(&argument_type) {
+                        return Err(format!(
+                            "`{}` expects {:?}, got {:?}{}",
+                            name, parameter, argument_type, near_miss(parameter, &argument_type)
+                        ));
+                    }
+                    argument_types.push(argument_type);
+                }
+                // A lambda without parameter types is checked again with the types `reduce` calls it
+                // with, so that its body is checked against them too
+                if let ("reduce", Expression::Lambda(function), [_, initial_type, Type::List(element_type)])
+                    = (name.as_str(), &arguments[0], argument_types.as_slice())
+                {
+                    if self.is_builtin(name) {
+                        let mut function = function.clone();
+                        for (parameter_type, called_with) in function.parameter_types.iter_mut().zip([initial_type, element_type]) {
+                            parameter_type.get_or_insert_with(|| called_with.clone());
+                        }
+                        argument_types[0] = self.check_function(&function, false)?;
+                    }
+                }
+                let refined = matches!(name.as_str(), "sort" | "sort_by" | "count_where" | "find" | "index_of" | "flatten" | "reduce" | "compare" | "is_empty" | "len") || MATH_FUNCTIONS.contains(&name.as_str());
+                if refined && self.is_builtin(name) {
+                    return builtin_type(name, &argument_types, return_type);
+                }
+                Ok(return_type)
+            }
+        }
+    }
+
+    /// Works out the type of one operation in a chain, given the type of
+    /// everything to its left.
+    fn operation_type(&mut self, left_type: Type, operator: &str, right: &Expression) -> Result<Type, String> {
+        let right_type = self.check_expression(right)?;
+        if operator == "or" {
+            return match left_type {
+                Type::Void => Ok(right_type),
+                left_type if left_type.accepts(&right_type) || right_type.accepts(&left_type) => {
+                    Ok(common_type(left_type, right_type))
+                }
+                left_type => Err(format!(
+                    "The default after `or` is {:?}, but the value before it is {:?}{}",
+                    right_type, left_type, near_miss(&left_type, &right_type)
+                )),
+            };
+        }
+        match (operator, &left_type, &right_type) {
+            // An operand whose type isn't known can't be checked any further
+            ("+" | "-" | "*" | "/", Type::Any, _) | ("+" | "-" | "*" | "/", _, Type::Any) => Ok(Type::Any),
+            ("in", _, Type::List(element_type)) if element_type.accepts(&left_type) => Ok(Type::Boolean),
+            ("in", Type::String, Type::String) => Ok(Type::Boolean),
+            ("in", _, _) => Err(format!("Cannot check whether {:?} is in {:?}", left_type, right_type)),
+            ("==" | "!=", _, _) if left_type.accepts(&right_type) || right_type.accepts(&left_type) => Ok(Type::Boolean),
+            ("==" | "!=", _, _) => Err(format!(
+                "Cannot compare {:?} and {:?} with `{}`, as they can never be equal{}",
+                left_type, right_type, operator, near_miss(&left_type, &right_type)
+            )),
+            ("<" | ">" | "<=" | ">=", _, _) if can_compare(&left_type, &right_type) => Ok(Type::Boolean),
+            ("<" | ">" | "<=" | ">=", _, _) => Err(format!(
+                "`{}` needs two numbers or two strings, got {:?} and {:?}",
+                operator, left_type, right_type
+            )),
+            ("..", Type::String | Type::Any, Type::String | Type::Any) => Ok(Type::String),
+            ("..", Type::String | Type::Integer | Type::Double | Type::Any, Type::String | Type::Integer | Type::Double | Type::Any)
+                if !self.strict_coercion => Ok(Type::String),
+            ("..", Type::String | Type::Any, number @ (Type::Integer | Type::Double))
+                | ("..", number @ (Type::Integer | Type::Double), _) if self.strict_coercion => Err(format!(
+                    "`..` only joins strings with `--strict-coercion` - use `str` to turn {:?} into a string",
+                    number
+                )),
+            ("..", _, _) => Err(format!("Cannot join {:?} and {:?} with `..`", left_type, right_type)),
+            ("+", Type::List(a), Type::List(b)) if a.accepts(b) => {
+                // Keep whichever element type is known, in case one list is empty
+                if **a == Type::Any { Ok(right_type.clone()) } else { Ok(left_type.clone()) }
+            }
+            ("*", Type::String, Type::Integer) | ("*", Type::Integer, Type::String) => Ok(Type::String),
+            ("+" | "-" | "*" | "/", Type::Integer, Type::Integer) => Ok(Type::Integer),
+            ("+" | "-" | "*" | "/", Type::Integer | Type::Double, Type::Integer | Type::Double) => Ok(Type::Double),
+            ("+" | "-" | "*" | "/", _, _) => {
+                // A number with a string is most likely a number read in as text
+                let hint = match (&left_type, &right_type) {
+                    (number @ (Type::Integer | Type::Double), found) | (found, number @ (Type::Integer | Type::Double)) => {
+                        near_miss(number, found)
+                    }
+                    _ => "",
+                };
+                Err(format!("Cannot apply `{}` to {:?} and {:?}{}", operator, left_type, right_type, hint))
+            }
+            _ => Err(format!("Unknown operator `{}`", operator)),
+        }
+    }
+}
+
+impl Default for TypeChecker {
+    fn default() -> Self {
+        TypeChecker::new()
+    }
+}
+
+/// A hint to add to a type error when the type found is easily mixed up
+/// with the one expected, such as a double where an integer should be.
+fn near_miss(expected: &Type, found: &Type) -> &'static str {
+    match (expected, found) {
+        (Type::Integer, Type::Double) => " - did you mean to use a whole number, or convert it with `as integer`?",
+        (Type::Double, Type::Integer) => {
+            " - did you mean to write it with a decimal point, like `1.0`, or convert it with `as double`?"
+        }
+        (Type::Integer, Type::String) => " - if the string holds a number, convert it with `as integer`",
+        (Type::Double, Type::String) => " - if the string holds a number, convert it with `as double`",
+        (Type::String, Type::Integer | Type::Double) => " - convert it to a string with `as string`",
+        (Type::List(expected), Type::List(found)) => near_miss(expected, found),
+        _ => "",
+    }
+}
+
+/// The type given back by a call to a built-in function whose signature is
+/// too loose to say, such as `sort`, which gives back the same type of list
+/// as it's given.
+fn builtin_type(name: &str, argument_types: &[Type], return_type: Type) -> Result<Type, String> {
+    match (name, argument_types) {
+        (_, [argument_type]) if MATH_FUNCTIONS.contains(&name) && !is_number(argument_type) => {
+            Err(format!("`{}` expects a number, got {:?}", name, argument_type))
+        }
+        ("compare", [a, b]) if !can_compare(a, b) => {
+            Err(format!("`compare` needs two numbers or two strings, got {:?} and {:?}", a, b))
+        }
+        ("index_of", [Type::List(element_type), target_type]) if !element_type.accepts(target_type) => {
+            Err(format!("`index_of` can't find {:?} in a list of {:?}", target_type, element_type))
+        }
+        ("index_of", [Type::String, target_type]) if !Type::String.accepts(target_type) => {
+            Err(format!("`index_of` can only find a string in a string, not {:?}", target_type))
+        }
+        ("index_of", [haystack_type, _]) if !matches!(haystack_type, Type::List(_) | Type::String | Type::Any) => {
+            Err(format!("`index_of` expects a list or string to look in, got {:?}", haystack_type))
         }
+        ("is_empty" | "len", [argument_type]) if !matches!(argument_type, Type::List(_) | Type::Map(_, _) | Type::String | Type::Any) => {
+            Err(format!("`{}` expects a list, map or string, got {:?}", name, argument_type))
+        }
+        ("sort", [Type::List(element_type)]) if !is_sortable(element_type) => {
+            Err(format!("`sort` can only sort numbers or strings, not {:?}", element_type))
+        }
+        ("sort_by", [Type::Function(_, key_type), _]) if !is_sortable(key_type) => Err(format!(
+            "`sort_by` needs its function to give back a number or a string to sort by, not {:?}",
+            key_type
+        )),
+        ("sort_by", [Type::Function(parameters, _), Type::List(element_type)]) if !parameters[0].accepts(element_type) => {
+            Err(format!("`sort_by`'s function takes {:?}, but the list holds {:?}", parameters[0], element_type))
+        }
+        ("sort", [list_type]) | ("sort_by", [_, list_type]) => Ok(list_type.clone()),
+        ("count_where" | "find", [Type::Function(parameters, _), Type::List(element_type)]) if !parameters[0].accepts(element_type) => {
+            Err(format!("`{}`'s function takes {:?}, but the list holds {:?}", name, parameters[0], element_type))
+        }
+        // Or void, if nothing matches
+        ("find", [_, Type::List(element_type)]) => Ok(*element_type.clone()),
+        ("flatten", [Type::List(element_type)]) => match element_type.as_ref() {
+            Type::List(_) | Type::Any => Ok(*element_type.clone()),
+            other => Err(format!("`flatten` expects a list of lists, but the list holds {:?}", other)),
+        },
+        // The value so far is passed back into the function, so it has to stay the same type
+        ("reduce", [Type::Function(parameters, result_type), initial_type, list_type]) => {
+            let element_type = match list_type {
+                Type::List(element_type) => element_type.as_ref(),
+                _ => &Type::Any,
+            };
+            if !parameters[0].accepts(initial_type) || !parameters[1].accepts(element_type) {
+                return Err(format!(
+                    "`reduce`'s function takes {:?} and {:?}, but was given {:?} and a list of {:?}",
+                    parameters[0], parameters[1], initial_type, element_type
+                ));
+            }
+            if !initial_type.accepts(result_type) {
+                return Err(format!(
+                    "`reduce`'s function should give back {:?} like the starting value, but gives back {:?}",
+                    initial_type, result_type
+                ));
+            }
+            Ok(if *initial_type == Type::Any { *result_type.clone() } else { initial_type.clone() })
+        }
+        _ => Ok(return_type),
     }
-}
\ No newline at end of file
+}
+
+/// The built-in maths functions, which take an integer or a double and give
+/// back a double.
+const MATH_FUNCTIONS: [&str; 6] = ["sqrt", "sin", "cos", "tan", "log", "exp"];
+
+fn is_number(value_type: &Type) -> bool {
+    matches!(value_type, Type::Integer | Type::Double | Type::Any)
+}
+
+/// Whether values of two types can be put in order together, which needs
+/// them both to be numbers or both to be strings.
+fn can_compare(a: &Type, b: &Type) -> bool {
+    match (a, b) {
+        (Type::Any, other) | (other, Type::Any) => is_sortable(other),
+        (Type::String, Type::String) => true,
+        _ => is_number(a) && is_number(b),
+    }
+}
+
+/// Whether values of a type can be keys in a map.
+fn is_key(value_type: &Type) -> bool {
+    matches!(value_type, Type::Integer | Type::Double | Type::String | Type::Boolean | Type::Any)
+}
+
+/// Whether values of a type can be put in order.
+fn is_sortable(value_type: &Type) -> bool {
+    matches!(value_type, Type::Integer | Type::Double | Type::String | Type::Any)
+}
+
+/// The type of a value that could come from either of two blocks. If they
+/// disagree, the value could be anything.
+fn common_type(a: Type, b: Type) -> Type {
+    match (a, b) {
+        (Type::Any, other) | (other, Type::Any) => other,
+        (a, b) if a.accepts(&b) => a,
+        _ => Type::Any,
+    }
+}
+
+/// The type of what `iter` (or a list comprehension) goes through in a
+/// value of type `iterable_type`, which for a map is its keys.
+fn iteration_type(iterable_type: Type) -> Result<Type, String> {
+    match iterable_type {
+        Type::String | Type::Integer | Type::Any => Ok(iterable_type),
+        Type::List(element_type) => Ok(*element_type),
+        Type::Map(key_type, _) => Ok(*key_type),
+        other => Err(format!("Cannot iterate over {:?}", other)),
+    }
+}
+
+/// Whether a value of type `source` might be converted to `target` with `as`.
+/// Strings might not hold a number, so that's only found out as it runs.
+fn can_cast(source: &Type, target: &Type) -> bool {
+    match (source, target) {
+        (Type::Any, _) | (_, Type::Any) | (_, Type::String) => true,
+        (Type::Integer | Type::Double | Type::String, Type::Integer | Type::Double) => true,
+        (Type::List(source), Type::List(target)) => can_cast(source, target),
+        (Type::Map(source_key, source), Type::Map(target_key, target)) => {
+            target_key.accepts(source_key) && can_cast(source, target)
+        }
+        _ => source == target,
+    }
+}
+
+/// A symbol table with the signatures of the built-in functions, and the
+/// constants `pi`, `e` and `version`.
+fn builtins() -> SymbolTable {
+    let mut symbol_table = SymbolTable::new();
+    // Signatures of the built-in functions
+    let builtins = [
+        ("str", vec![Type::Any], Type::String),
+        ("int", vec![Type::Any], Type::Integer),
+        ("double", vec![Type::Any], Type::Double),
+        ("fixed", vec![Type::Double, Type::Integer], Type::String),
+        ("is_empty", vec![Type::Any], Type::Boolean),
+        ("len", vec![Type::Any], Type::Integer),
+        ("byte_len", vec![Type::String], Type::Integer),
+        ("compare", vec![Type::Any, Type::Any], Type::Integer),
+        ("equals", vec![Type::Any, Type::Any], Type::Boolean),
+        // Or void, if it isn't there
+        ("index_of", vec![Type::Any, Type::Any], Type::Integer),
+        ("almost_equal", vec![Type::Double, Type::Double], Type::Boolean),
+        ("pretty", vec![Type::Any], Type::String),
+        ("json", vec![Type::Any], Type::String),
+        // What's in the JSON is only known as it runs
+        ("parse_json", vec![Type::String], Type::Any),
+        ("to_bytes", vec![Type::Any], Type::List(Box::new(Type::Integer))),
+        // Like `parse_json`, what the bytes hold is only known as it runs
+        ("from_bytes", vec![Type::List(Box::new(Type::Integer))], Type::Any),
+        ("read_file", vec![Type::String], Type::String),
+        // The lines of a file work like a list of strings
+        ("lines_of", vec![Type::String], Type::List(Box::new(Type::String))),
+        ("write_file", vec![Type::String, Type::String], Type::Void),
+        ("append_file", vec![Type::String, Type::String], Type::Void),
+        // Or void, if the variable isn't set
+        ("get_env", vec![Type::String], Type::String),
+        ("pretty_by", vec![Type::Any, Type::Integer], Type::String),
+        (
+            "map",
+            vec![Type::Function(vec![Type::Any], Box::new(Type::Any)), Type::List(Box::new(Type::Any))],
+            Type::List(Box::new(Type::Any)),
+        ),
+        (
+            "filter",
+            vec![Type::Function(vec![Type::Any], Box::new(Type::Boolean)), Type::List(Box::new(Type::Any))],
+            Type::List(Box::new(Type::Any)),
+        ),
+        (
+            "count_where",
+            vec![Type::Function(vec![Type::Any], Box::new(Type::Boolean)), Type::List(Box::new(Type::Any))],
+            Type::Integer,
+        ),
+        (
+            "find",
+            vec![Type::Function(vec![Type::Any], Box::new(Type::Boolean)), Type::List(Box::new(Type::Any))],
+            Type::Any,
+        ),
+        ("flatten", vec![Type::List(Box::new(Type::Any))], Type::List(Box::new(Type::Any))),
+        ("sort", vec![Type::List(Box::new(Type::Any))], Type::List(Box::new(Type::Any))),
+        (
+            "sort_by",
+            vec![Type::Function(vec![Type::Any], Box::new(Type::Any)), Type::List(Box::new(Type::Any))],
+            Type::List(Box::new(Type::Any)),
+        ),
+        (
+            "reduce",
+            vec![Type::Function(vec![Type::Any, Type::Any], Box::new(Type::Any)), Type::Any, Type::List(Box::new(Type::Any))],
+            Type::Any,
+        ),
+        // A range works like a list of integers
+        ("range", vec![Type::Integer, Type::Integer], Type::List(Box::new(Type::Integer))),
+        ("range_by", vec![Type::Integer, Type::Integer, Type::Integer], Type::List(Box::new(Type::Integer))),
+    ];
+    for (name, parameters, return_type) in builtins {
+        symbol_table.insert(name.to_string(), Type::Function(parameters, Box::new(return_type)));
+    }
+    for name in MATH_FUNCTIONS {
+        symbol_table.insert(name.to_string(), Type::Function(vec![Type::Any], Box::new(Type::Double)));
+    }
+    // The constants every program starts with
+    symbol_table.insert("pi".to_string(), Type::Double);
+    symbol_table.insert("e".to_string(), Type::Double);
+    symbol_table.insert("version".to_string(), Type::String);
+    symbol_table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    /// Checks a program, giving back the checker so that a test can ask it
+    /// what it knows afterwards, or the messages of any errors.
+    fn check(source: &str) -> Result<TypeChecker, Vec<String>> {
+        let messages = |errors: Vec<ClariceError>| errors.iter().map(ToString::to_string).collect::<Vec<_>>();
+        let program = Parser::new(Lexer::new(source)).parse_only().map_err(messages)?;
+        let mut type_checker = TypeChecker::new();
+        type_checker.check(&program).map_err(messages)?;
+        Ok(type_checker)
+    }
+
+    fn type_of(type_checker: &TypeChecker, name: &str) -> Type {
+        type_checker.symbol_table.lookup(name).unwrap().symbol_type.clone()
+    }
+
+    #[test]
+    fn with_only_changes_a_type_inside_it() {
+        let type_checker = check("set x to 1\nwith x as \"s\" print x in \"sun\"").unwrap();
+        assert_eq!(type_of(&type_checker, "x"), Type::Integer);
+        let inside = check("set x to 1\nwith x as \"s\" print x - 1").err().unwrap();
+        assert!(inside[0].starts_with("line 2: Cannot apply `-` to String and Integer"), "{:?}", inside);
+        let after = check("set x to 1\nwith x as \"s\" print x\nprint x - 1\nprint x in \"sun\"").err().unwrap();
+        assert_eq!(after.len(), 1, "{:?}", after);
+        assert!(after[0].starts_with("line 4: ") && after[0].contains("Integer"), "{:?}", after);
+    }
+
+    #[test]
+    fn reduce_checks_its_function_against_the_accumulator_and_elements() {
+        let type_checker = check("set total to reduce (fn(a, b) do a + b end) 0 [1, 2]").unwrap();
+        assert_eq!(type_of(&type_checker, "total"), Type::Integer);
+        let errors = check("print reduce (fn(a, b) do a + b end) \"x\" [1, 2]").err().unwrap();
+        assert!(errors[0].starts_with("line 1: Cannot apply `+` to String and Integer"), "{:?}", errors);
+        let errors = check("fn add(a: integer, b: integer): integer do a + b end\nprint reduce add \"x\" [1, 2]").err().unwrap();
+        assert!(errors[0].starts_with("line 2: `reduce`'s function takes Integer and Integer"), "{:?}", errors);
+        assert!(check("print reduce (fn(a) do a end) 0 [1, 2]").is_err());
+    }
+
+    #[test]
+    fn errors_are_on_the_line_of_the_innermost_statement() {
+        let errors = check("set x to 1\nwhere x in [1] do\n    print x\n    print x .. [x]\nend\nwith y as \"s\"\n    print y - 1").err().unwrap();
+        assert_eq!(errors, [
+            "line 4: Cannot join Integer and List(Integer) with `..`",
+            "line 7: Cannot apply `-` to String and Integer - if the string holds a number, convert it with `as integer`",
+        ]);
+    }
+}
@@ -0,0 +1,88 @@
+// allocations.rs - Tests for what running a program allocates
+//
+// These count allocations with a global allocator, so they're kept in their
+// own test binary rather than swapping the allocator for every other test.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+use clarice::interpreter::Environment;
+use clarice::lexer::Lexer;
+use clarice::parser::Parser;
+use clarice::type_checker::TypeChecker;
+
+/// Counts the allocations made on each thread, and how many bytes they
+/// were for, so a test can see what a program allocates without the
+/// other tests running alongside it counting.
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<(usize, usize)> = const { Cell::new((0, 0)) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // The counter can be gone while the thread is shutting down
+        let _ = ALLOCATIONS.try_with(|count| {
+            let (allocations, bytes) = count.get();
+            count.set((allocations + 1, bytes + layout.size()));
+        });
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, pointer: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(pointer, layout) }
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// How many allocations running a checked program makes, and how many
+/// bytes they add up to, not counting parsing and checking it.
+fn allocations(environment: &mut Environment, source: &str) -> (usize, usize) {
+    environment.output = Box::new(std::io::sink());
+    let program = Parser::new(Lexer::new(source)).parse(&mut TypeChecker::new()).unwrap();
+    let (allocations, bytes) = ALLOCATIONS.with(|count| count.get());
+    environment.interpret(program).unwrap();
+    let (allocations_after, bytes_after) = ALLOCATIONS.with(|count| count.get());
+    (allocations_after - allocations, bytes_after - bytes)
+}
+
+#[test]
+fn interning_saves_an_allocation_per_character() {
+    let mut environment = Environment::new();
+    let source = "iter c in \"abcd\" * 25000 do pass end";
+    let (interned, _) = allocations(&mut environment, source);
+    environment.intern_strings = false;
+    let (not_interned, _) = allocations(&mut environment, source);
+    // About 300,000 allocations without interning, and 200,000 with it
+    assert!(
+        not_interned.saturating_sub(interned) >= 99_000,
+        "{} allocations interned, {} not interned", interned, not_interned
+    );
+}
+
+/// Makes a list of 100,000 elements and reads it `reads` times.
+fn read_list(reads: usize) -> String {
+    format!("set xs to [x for x in 100000]\niter i in {} do set ys to xs end", reads)
+}
+
+#[test]
+fn reading_a_list_doesnt_copy_it() {
+    let (_, making) = allocations(&mut Environment::new(), &read_list(0));
+    let (_, reading) = allocations(&mut Environment::new(), &read_list(2000));
+    // Each copy of the list would be a few megabytes
+    assert!(reading - making < 1_000_000, "reading the list 2000 times allocated {} bytes", reading - making);
+}
+
+/// Run with `cargo test --release --test allocations -- --ignored --nocapture`
+/// to see the timing. Copying the list on every read took about 1.9s.
+#[test]
+#[ignore]
+fn benchmark_reading_a_list() {
+    let program = Parser::new(Lexer::new(&read_list(2000))).parse(&mut TypeChecker::new()).unwrap();
+    let start = std::time::Instant::now();
+    Environment::new().interpret(program).unwrap();
+    println!("Reading a 100,000 element list 2000 times took {:?}", start.elapsed());
+}
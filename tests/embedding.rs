@@ -0,0 +1,53 @@
+// embedding.rs - Tests for running Clarice from a Rust program
+
+use clarice::error::RuntimeError;
+use clarice::interpreter::{Environment, Value};
+use clarice::lexer::Lexer;
+use clarice::parser::Parser;
+use clarice::symbol_table::Type;
+use clarice::type_checker::TypeChecker;
+
+/// An environment with a `shout` function written in Rust, and a type
+/// checker that knows its type.
+fn with_shout() -> (Environment, TypeChecker) {
+    let mut environment = Environment::new();
+    environment.register_native("shout", |arguments| match arguments.as_slice() {
+        [Value::String(s)] => Ok(Value::String(s.to_uppercase().into())),
+        other => Err(RuntimeError::new(format!("`shout` expects a string, got {:?}", other))),
+    });
+    let mut type_checker = TypeChecker::new();
+    type_checker.declare("shout".to_string(), Type::Function(vec![Type::String], Box::new(Type::String)));
+    (environment, type_checker)
+}
+
+#[test]
+fn natives_can_be_called_from_a_program() {
+    let (mut environment, mut type_checker) = with_shout();
+    let program = Parser::new(Lexer::new("shout \"hi\" .. \"!\"")).parse(&mut type_checker).unwrap();
+    assert_eq!(environment.interpret(program), Ok(Some(Value::String("HI!".into()))));
+}
+
+#[test]
+fn natives_are_type_checked_and_their_errors_reach_the_program() {
+    let (mut environment, mut type_checker) = with_shout();
+    let errors = Parser::new(Lexer::new("shout 1")).parse(&mut type_checker).unwrap_err();
+    assert_eq!(errors[0].message, "`shout` expects String, got Integer - convert it to a string with `as string`");
+
+    let program = Parser::new(Lexer::new("shout 1")).parse_only().unwrap();
+    assert_eq!(
+        environment.interpret(program),
+        Err(RuntimeError::new("`shout` expects a string, got [Integer(1)]".to_string()))
+    );
+}
+
+#[test]
+fn variables_can_be_snapshotted_and_restored() {
+    let mut environment = Environment::new();
+    environment.set("x".to_string(), Value::Integer(1));
+    let snapshot = environment.snapshot();
+    let program = Parser::new(Lexer::new("set x to 2")).parse_only().unwrap();
+    environment.interpret(program).unwrap();
+    assert_eq!(environment.get("x"), Some(&Value::Integer(2)));
+    environment.restore(snapshot);
+    assert_eq!(environment.get("x"), Some(&Value::Integer(1)));
+}